@@ -1,24 +1,35 @@
-use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::SeekFrom,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use axum::{
     body::BodyDataStream,
     extract::{ConnectInfo, Query, Request, State},
     http::StatusCode,
+    response::{IntoResponse, Response},
     Json,
 };
 use serde::Deserialize;
 use tokio::{
-    fs::File,
-    io::{AsyncWriteExt, BufWriter},
+    fs::{self, File, OpenOptions},
+    io::{AsyncSeekExt, AsyncWriteExt, BufWriter},
     sync::watch,
     time,
 };
 use tokio_stream::StreamExt;
 
 use crate::{
+    guard::{Admission, Endpoint},
     mission::Mission,
-    model::{DeviceMessage, FileInfo, FileRequest, FileResponse, UploadParam},
+    model::{DeviceMessage, FileInfo, FileRequest, FileResponse, UploadOffset, UploadParam},
     server::ServerHandle,
+    throttle::global_throttle,
 };
 
 #[derive(Clone)]
@@ -30,22 +41,67 @@ pub async fn handle_register(
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Json(payload): Json<DeviceMessage>,
-) -> Result<Json<DeviceMessage>, ()> {
+) -> Result<Json<DeviceMessage>, StatusCode> {
+    admit(&state, addr, &payload.fingerprint, Endpoint::Register).await?;
     state
         .handel
         .insert_device(payload.fingerprint.to_owned(), addr, payload)
         .await;
     match state.handel.get_myself().await {
         Some(device) => Ok(Json(device)),
-        None => Err(()),
+        None => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+pub(crate) async fn admit(
+    state: &AppState,
+    addr: SocketAddr,
+    fingerprint: &str,
+    endpoint: Endpoint,
+) -> Result<(), StatusCode> {
+    match state
+        .handel
+        .admit(addr.ip(), Some(fingerprint.to_string()), endpoint)
+        .await
+    {
+        Admission::Allowed => Ok(()),
+        Admission::Blocked => Err(StatusCode::FORBIDDEN),
+        Admission::RateLimited => Err(StatusCode::TOO_MANY_REQUESTS),
+    }
+}
+
+pub async fn handle_info(State(state): State<AppState>) -> Result<Json<DeviceMessage>, StatusCode> {
+    match state.handel.get_myself().await {
+        Some(device) => Ok(Json(device)),
+        None => Err(StatusCode::INTERNAL_SERVER_ERROR),
     }
 }
 
+#[derive(Deserialize)]
+pub struct PinParam {
+    pub pin: Option<String>,
+}
+
 pub async fn handle_prepare_upload(
     State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(param): Query<PinParam>,
     Json(payload): Json<FileRequest>,
-) -> Result<Json<FileResponse>, StatusCode> {
+) -> Result<Response, StatusCode> {
     log::info!("prepare_upload: {:?}", &payload);
+    admit(
+        &state,
+        addr,
+        &payload.info.fingerprint,
+        Endpoint::PrepareUpload,
+    )
+    .await?;
+    // 校验 PIN
+    if let Some(pin) = state.handel.get_pin().await {
+        if param.pin.as_ref() != Some(&pin) {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    }
     let device = if let Some(device) = state
         .handel
         .get_device(payload.info.fingerprint.clone())
@@ -56,6 +112,44 @@ pub async fn handle_prepare_upload(
         return Err(StatusCode::FORBIDDEN);
     };
 
+    match create_mission(&state, addr, payload, device).await {
+        Ok(Some(file_resp)) => Ok(Json(file_resp).into_response()),
+        Ok(None) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(resp) => Ok(resp),
+    }
+}
+
+// v1 和 v2 共用：检查大小、询问是否同意并新建下载任务，文本消息返回 None
+pub(crate) async fn create_mission(
+    state: &AppState,
+    addr: SocketAddr,
+    payload: FileRequest,
+    device: DeviceMessage,
+) -> Result<Option<FileResponse>, Response> {
+    // 文件名可以带相对路径，但不能逃出保存目录
+    if payload
+        .files
+        .values()
+        .any(|file| file.relative_path().is_none())
+    {
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    }
+
+    // 超过大小限制时整个请求都不接受
+    let sizes = payload.files.values().map(|file| file.size).collect();
+    if let Err(e) = state.handel.check_size(sizes).await {
+        log::warn!("reject prepare-upload from {}: {e}", payload.info.alias);
+        return Err(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+    }
+
+    // 文本消息直接交给外部显示，不需要上传
+    if payload.text().is_some() {
+        return match state.handel.receive_text(payload).await {
+            true => Ok(None),
+            false => Err(StatusCode::FORBIDDEN.into_response()),
+        };
+    }
+
     // 获取同意下载的文件 id
     let agreed_ids = state.handel.prepare_upload(payload.clone()).await;
     log::info!("{agreed_ids:?}");
@@ -65,19 +159,32 @@ pub async fn handle_prepare_upload(
         .into_iter()
         .filter(|(file_id, _)| agreed_ids.contains(file_id))
         .collect();
-    let mission = Mission::new(files, device);
-    // 新建下载任务
-    state
-        .handel
-        .insert_mission(mission.id.clone(), mission.clone())
-        .await;
+    // 同意的文件放不进保存目录时拒绝整个请求
+    if !files.is_empty() {
+        let accepted = FileRequest {
+            info: payload.info,
+            files: files.clone(),
+        };
+        if let Err(e) = state.handel.check_disk_space(accepted).await {
+            return Err((StatusCode::INSUFFICIENT_STORAGE, e).into_response());
+        }
+    }
+    let mut mission = Mission::new(files, device);
+    mission.sender_ip = Some(addr.ip());
+    // 新建下载任务，全部拒绝时不需要
+    if !mission.info_map.is_empty() {
+        state
+            .handel
+            .insert_mission(mission.id.clone(), mission.clone())
+            .await;
+    }
 
     let file_resp: FileResponse = FileResponse {
         session_id: mission.id,
         files: mission.id_token_map,
     };
     log::info!("agreed upload: {:?}", file_resp);
-    Ok(Json(file_resp))
+    Ok(Some(file_resp))
 }
 
 pub async fn handle_upload(
@@ -85,36 +192,174 @@ pub async fn handle_upload(
     param: Query<UploadParam>,
     request: Request,
 ) -> Result<(), StatusCode> {
-    let param = param.0;
+    receive_upload(&state, param.0, request).await
+}
+
+pub(crate) async fn receive_upload(
+    state: &AppState,
+    param: UploadParam,
+    request: Request,
+) -> Result<(), StatusCode> {
     log::info!("upload: {:?}", param);
+    let offset = param.offset.unwrap_or(0);
+    let (session_id, file_id) = (param.session_id.clone(), param.file_id.clone());
     let (file, tx) = match state.handel.get_file_info(param).await {
         Some(r) => r,
         None => return Err(StatusCode::FORBIDDEN),
     };
-    let store_path = state.handel.get_store_path().await;
+    let store_path = state
+        .handel
+        .get_destination(session_id.clone(), file_id.clone())
+        .await;
+    // 续传的位置不能超过已经收到的字节数
+    if offset > 0 && offset > received_bytes(&store_path, &file).await {
+        return Err(StatusCode::RANGE_NOT_SATISFIABLE);
+    }
     let body_stream = request.into_body().into_data_stream();
-    save_to_file(store_path, &file.file_name, body_stream, tx)
+    let (res, status) = tokio::select! {
+        res = save_to_file(store_path, &file, body_stream, tx, offset) => match res {
+            Ok(path) => (Ok(path), Ok(())),
+            Err(e) => {
+                log::error!("Error saving file: {}", e);
+                let status = match e.downcast_ref::<SizeMismatch>() {
+                    Some(_) => StatusCode::BAD_REQUEST,
+                    None => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                (Err(e.to_string()), Err(status))
+            }
+        },
+        // 服务器关闭，放弃传输
+        _ = state.handel.cancelled() => (
+            Err("server shutting down".to_string()),
+            Err(StatusCode::SERVICE_UNAVAILABLE),
+        ),
+    };
+    state.handel.upload_finished(session_id, file_id, res).await;
+    status
+}
+
+// 返回已经写入 `.part` 文件的字节数，发送方从这里续传
+pub async fn handle_upload_offset(
+    State(state): State<AppState>,
+    param: Query<UploadParam>,
+) -> Result<Json<UploadOffset>, StatusCode> {
+    let param = param.0;
+    let mission = state
+        .handel
+        .get_mission(param.session_id)
         .await
-        .map_err(|e| {
-            log::error!("Error saving file: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })
+        .ok_or(StatusCode::FORBIDDEN)?;
+    let file = match mission.id_token_map.get(&param.file_id) {
+        Some(token) if *token == param.token => mission.info_map.get(&param.file_id),
+        _ => None,
+    }
+    .ok_or(StatusCode::FORBIDDEN)?;
+    let store_path = state
+        .handel
+        .get_destination(mission.id.clone(), param.file_id)
+        .await;
+    Ok(Json(UploadOffset {
+        offset: received_bytes(&store_path, file).await,
+    }))
+}
+
+// 收到的数据和 prepare-upload 时声明的大小不一致
+#[derive(Debug)]
+struct SizeMismatch {
+    expected: u64,
+    received: u64,
+}
+
+impl fmt::Display for SizeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.received > self.expected {
+            true => write!(f, "file exceeds announced size of {} bytes", self.expected),
+            false => write!(
+                f,
+                "received {} of {} announced bytes",
+                self.received, self.expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SizeMismatch {}
+
+// 传输过程中先写入 `<文件名>.part`，完成后再重命名
+fn part_path(file_path: &Path) -> PathBuf {
+    let mut name = file_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    file_path.with_file_name(name)
+}
+
+async fn received_bytes(dir: &Path, file_info: &FileInfo) -> u64 {
+    let Some(path) = file_info.relative_path() else {
+        return 0;
+    };
+    match fs::metadata(part_path(&dir.join(path))).await {
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    }
+}
+
+// 最深的已存在的上级目录
+async fn existing_ancestor(path: &Path) -> &Path {
+    for ancestor in path.ancestors() {
+        if fs::metadata(ancestor).await.is_ok() {
+            return ancestor;
+        }
+    }
+    path
+}
+
+async fn check_inside(
+    root: &Path,
+    dir: &Path,
+    file_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match fs::canonicalize(dir).await?.starts_with(root) {
+        true => Ok(()),
+        false => Err(format!("{file_path:?} is outside of {root:?}").into()),
+    }
 }
 
 async fn save_to_file(
     dir: PathBuf,
-    file_name: &str,
+    file_info: &FileInfo,
     stream: BodyDataStream,
     progress: watch::Sender<usize>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let file_path = dir.join(file_name);
-    let file = File::create(file_path).await?;
+    offset: u64,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    // `relative_path` 只包含普通的路径组成部分
+    let file_path = file_info
+        .relative_path()
+        .map(|path| dir.join(path))
+        .ok_or(format!("invalid file name `{}`", file_info.file_name))?;
+    if let Some(parent) = file_path.parent() {
+        // 保存目录由接收方设置，可以直接创建
+        fs::create_dir_all(&dir).await?;
+        let root = fs::canonicalize(&dir).await?;
+        // 已有的子目录可能是指向外部的符号链接，创建子目录之前先检查
+        check_inside(&root, existing_ancestor(parent).await, &file_path).await?;
+        fs::create_dir_all(parent).await?;
+        check_inside(&root, parent, &file_path).await?;
+    }
+    let part_path = part_path(&file_path);
+    let file = match offset {
+        0 => File::create(&part_path).await?,
+        offset => {
+            // 丢掉 `offset` 之后可能写了一半的数据
+            let mut file = OpenOptions::new().write(true).open(&part_path).await?;
+            file.set_len(offset).await?;
+            file.seek(SeekFrom::Start(offset)).await?;
+            file
+        }
+    };
     let mut writer = BufWriter::new(file);
-    let mut stream =
-        stream.map(|res| res.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err)));
+    let mut stream = stream.map(|res| res.map_err(std::io::Error::other));
     // 初始化定时器
     let mut interval = time::interval(Duration::from_millis(100));
-    let mut total_written = 0usize;
+    let mut total_written = offset as usize;
 
     // 更新进度
     loop {
@@ -125,10 +370,23 @@ async fn save_to_file(
             chunk_res = stream.next() => {
                 match chunk_res {
                     Some(Ok(chunk)) => {
+                        // 不能超过 prepare-upload 时声明的大小
+                        let received = total_written as u64 + chunk.len() as u64;
+                        if received > file_info.size {
+                            drop(writer);
+                            let _ = fs::remove_file(&part_path).await;
+                            return Err(Box::new(SizeMismatch {
+                                expected: file_info.size,
+                                received,
+                            }));
+                        }
+                        global_throttle().consume(chunk.len()).await;
                         writer.write_all(&chunk).await?;
                         total_written += chunk.len();
                     }
                     Some(Err(err)) => {
+                        // 保留已经收到的数据，之后可以续传
+                        writer.flush().await?;
                         return Err(Box::new(err));
                     }
                     None => {
@@ -141,7 +399,22 @@ async fn save_to_file(
         }
     }
     writer.flush().await?;
-    Ok(())
+    // 数据不完整，保留 `.part` 文件以便续传
+    if (total_written as u64) < file_info.size {
+        return Err(Box::new(SizeMismatch {
+            expected: file_info.size,
+            received: total_written as u64,
+        }));
+    }
+    // 保留发送方文件的修改时间
+    if let Some(metadata) = &file_info.metadata {
+        let file = writer.into_inner().into_std().await;
+        if let Err(e) = file.set_times(metadata.file_times()) {
+            log::warn!("set file times of {file_path:?} error: {e}");
+        }
+    }
+    fs::rename(part_path, &file_path).await?;
+    Ok(file_path)
 }
 
 #[derive(Deserialize)]
@@ -156,4 +429,69 @@ pub async fn handel_cancel(State(state): State<AppState>, session_id: Query<Sess
 }
 
 // 3.2 HTTP Legacy Mode 未实现
-// 5 下载 API 未实现
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+
+    use super::*;
+    use crate::model::FileMetadata;
+
+    #[tokio::test]
+    async fn test_save_to_file_size() {
+        let dir = std::env::temp_dir().join(format!("upload-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let file = FileInfo {
+            id: "1".to_string(),
+            file_name: "a.txt".to_string(),
+            size: 3,
+            metadata: Some(FileMetadata {
+                modified: Some("2020-01-02T03:04:05.000Z".to_string()),
+                accessed: None,
+            }),
+            ..Default::default()
+        };
+        let save = |body: &'static str| {
+            let stream = Body::from(body).into_data_stream();
+            save_to_file(dir.clone(), &file, stream, watch::channel(0).0, 0)
+        };
+
+        let err = save("abcd").await.unwrap_err();
+        assert!(err.downcast_ref::<SizeMismatch>().is_some());
+        assert!(!dir.join("a.txt.part").exists());
+        let err = save("ab").await.unwrap_err();
+        assert!(err.downcast_ref::<SizeMismatch>().is_some());
+        assert!(dir.join("a.txt.part").exists());
+        assert_eq!(save("abc").await.unwrap(), dir.join("a.txt"));
+        let modified = fs::metadata(dir.join("a.txt")).await.unwrap().modified();
+        assert_eq!(
+            chrono::DateTime::<chrono::Utc>::from(modified.unwrap()).to_rfc3339(),
+            "2020-01-02T03:04:05+00:00"
+        );
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_save_to_file_symlink() {
+        let root = std::env::temp_dir().join(format!("upload-{}", uuid::Uuid::new_v4()));
+        let (store, outside) = (root.join("store"), root.join("outside"));
+        fs::create_dir_all(&store).await.unwrap();
+        fs::create_dir_all(&outside).await.unwrap();
+        fs::symlink(&outside, store.join("link")).await.unwrap();
+        let file = FileInfo {
+            id: "1".to_string(),
+            file_name: "link/new/a.txt".to_string(),
+            size: 3,
+            ..Default::default()
+        };
+        let stream = Body::from("abc").into_data_stream();
+        let res = save_to_file(store.clone(), &file, stream, watch::channel(0).0, 0).await;
+        assert!(res.is_err());
+        // 拒绝之前不能在外部创建目录
+        assert!(!outside.join("new").exists());
+
+        fs::remove_dir_all(root).await.unwrap();
+    }
+}
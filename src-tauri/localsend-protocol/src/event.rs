@@ -0,0 +1,115 @@
+use std::{collections::HashSet, net::SocketAddr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    mission::MissionSummary,
+    model::{DeviceMessage, FileRequest},
+};
+
+// 以 JSON 行的形式输出的服务器事件
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum Event {
+    #[serde(rename_all = "camelCase")]
+    Listening { addr: SocketAddr },
+    #[serde(rename_all = "camelCase")]
+    DeviceConnect {
+        addr: SocketAddr,
+        device: DeviceMessage,
+    },
+    // 等待 `accept`/`decline` 命令
+    #[serde(rename_all = "camelCase")]
+    PrepareUpload {
+        request_id: String,
+        request: FileRequest,
+        free_space: Option<u64>, // 保存目录的可用空间（字节）
+    },
+    #[serde(rename_all = "camelCase")]
+    AutoAccepted { request: FileRequest },
+    #[serde(rename_all = "camelCase")]
+    AutoDeclined { request: FileRequest },
+    // 同意后因为错误拒绝，例如磁盘空间不足
+    #[serde(rename_all = "camelCase")]
+    Declined {
+        request: FileRequest,
+        reason: String,
+    },
+    // 可以用 `cancel` 命令取消
+    #[serde(rename_all = "camelCase")]
+    SessionStarted {
+        session_id: String,
+        sender: DeviceMessage,
+        files: Vec<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    TextReceived { sender: DeviceMessage, text: String },
+    #[serde(rename_all = "camelCase")]
+    Progress { file_id: String, bytes: usize },
+    #[serde(rename_all = "camelCase")]
+    Finished { file_id: String, bytes: usize },
+    // 任务完成、被取消或过期
+    #[serde(rename_all = "camelCase")]
+    SessionFinished { summary: MissionSummary },
+    #[serde(rename_all = "camelCase")]
+    Cancelled {
+        session_id: Option<String>,
+        sender: Option<DeviceMessage>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Error { message: String },
+}
+
+// 从 JSON 行读取的命令
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "camelCase")]
+pub enum Command {
+    // `files` 为空时同意全部文件
+    #[serde(rename_all = "camelCase")]
+    Accept {
+        request_id: String,
+        #[serde(default)]
+        files: Option<HashSet<String>>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Decline {
+        request_id: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Cancel {
+        session_id: String,
+    },
+    // 屏蔽设备，不影响已经收到的请求
+    #[serde(rename_all = "camelCase")]
+    Block {
+        fingerprint: String,
+    },
+    Refresh,
+    Shutdown,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_json() {
+        let command: Command =
+            serde_json::from_str(r#"{"command":"accept","requestId":"1","files":["a"]}"#).unwrap();
+        assert!(matches!(
+            command,
+            Command::Accept { request_id, files: Some(files) } if request_id == "1" && files.contains("a")
+        ));
+        let command: Command = serde_json::from_str(r#"{"command":"refresh"}"#).unwrap();
+        assert!(matches!(command, Command::Refresh));
+
+        let event = Event::Progress {
+            file_id: "a".to_string(),
+            bytes: 10,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"progress","fileId":"a","bytes":10}"#
+        );
+    }
+}
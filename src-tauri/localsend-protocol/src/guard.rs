@@ -0,0 +1,118 @@
+use std::{collections::HashMap, net::IpAddr, time::Instant};
+
+use serde::{Deserialize, Serialize};
+
+// 令牌桶参数，`burst` 为 0 时不限流
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RateLimit {
+    pub burst: u32,      // 桶容量，允许的突发请求数
+    pub per_minute: u32, // 每分钟补充的令牌数
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            burst: 10,
+            per_minute: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Register,
+    PrepareUpload,
+    PrepareDownload,
+}
+
+// 请求检查结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Allowed,
+    Blocked,     // 403
+    RateLimited, // 429
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// 按 IP 和接口分别限流
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: HashMap<(IpAddr, Endpoint), TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn check(&mut self, limit: &RateLimit, ip: IpAddr, endpoint: Endpoint) -> bool {
+        self.check_at(limit, ip, endpoint, Instant::now())
+    }
+
+    fn check_at(
+        &mut self,
+        limit: &RateLimit,
+        ip: IpAddr,
+        endpoint: Endpoint,
+        now: Instant,
+    ) -> bool {
+        if limit.burst == 0 {
+            return true;
+        }
+        let capacity = limit.burst as f64;
+        let refill = |bucket: &TokenBucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            (bucket.tokens + elapsed * limit.per_minute as f64 / 60.0).min(capacity)
+        };
+        // 清理已经补满的桶，避免占用越来越多的内存
+        if self.buckets.len() > 1024 {
+            self.buckets.retain(|_, bucket| refill(bucket) < capacity);
+        }
+        let bucket = self.buckets.entry((ip, endpoint)).or_insert(TokenBucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limit = RateLimit {
+            burst: 2,
+            per_minute: 60,
+        };
+        let mut limiter = RateLimiter::default();
+        let ip: IpAddr = "192.168.1.2".parse().unwrap();
+        let now = Instant::now();
+        assert!(limiter.check_at(&limit, ip, Endpoint::Register, now));
+        assert!(limiter.check_at(&limit, ip, Endpoint::Register, now));
+        assert!(!limiter.check_at(&limit, ip, Endpoint::Register, now));
+        // 其他接口和其他 IP 不受影响
+        assert!(limiter.check_at(&limit, ip, Endpoint::PrepareUpload, now));
+        assert!(limiter.check_at(
+            &limit,
+            "192.168.1.3".parse().unwrap(),
+            Endpoint::Register,
+            now
+        ));
+        // 每秒补充一个令牌
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.check_at(&limit, ip, Endpoint::Register, later));
+        assert!(!limiter.check_at(&limit, ip, Endpoint::Register, later));
+    }
+}
@@ -0,0 +1,364 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    mission::{FileState, MissionState, MissionSummary},
+    model::FileInfo,
+    sender::SendResult,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Outcome {
+    Completed,
+    PartiallyFailed, // 部分文件失败
+    Failed,
+    Declined,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryFile {
+    pub id: String,
+    pub file_name: String,
+    pub size: u64,
+    pub path: Option<PathBuf>, // 发送的源文件或接收后保存的位置
+    pub sha256: Option<String>,
+    pub error: Option<String>,
+}
+
+// 一次发送或接收会话的记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+    pub id: String,
+    pub session_id: Option<String>,
+    pub direction: Direction,
+    pub peer_alias: String,
+    pub peer_fingerprint: String,
+    pub started_at: i64, // Unix 时间戳（毫秒）
+    pub duration_ms: u64,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+    pub files: Vec<HistoryFile>,
+}
+
+impl HistoryEntry {
+    fn new(direction: Direction, session_id: Option<String>) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            session_id,
+            direction,
+            peer_alias: String::new(),
+            peer_fingerprint: String::new(),
+            started_at: now_millis(),
+            duration_ms: 0,
+            outcome: Outcome::Completed,
+            error: None,
+            files: vec![],
+        }
+    }
+
+    // 接收任务结束时由 `MissionFinished` 生成
+    pub fn received(summary: &MissionSummary) -> Self {
+        let mut entry = Self::new(Direction::Received, Some(summary.id.clone()));
+        entry.peer_alias = summary.sender_device.alias.clone();
+        entry.peer_fingerprint = summary.sender_device.fingerprint.clone();
+        entry.started_at = summary.created_at;
+        entry.files = summary
+            .files
+            .iter()
+            .map(|(info, status)| HistoryFile {
+                id: info.id.clone(),
+                file_name: info.file_name.clone(),
+                size: info.size,
+                path: status.path.clone(),
+                sha256: info.sha256.clone(),
+                error: match status.state {
+                    FileState::Finished => None,
+                    _ => Some(status.error.clone().unwrap_or("not received".to_string())),
+                },
+            })
+            .collect();
+        entry.finish(match summary.state {
+            MissionState::Cancelled => Some(Outcome::Cancelled),
+            _ => None,
+        });
+        entry.duration_ms = summary
+            .finished_at
+            .saturating_sub(summary.created_at)
+            .max(0) as u64;
+        entry
+    }
+
+    pub fn sent(result: &SendResult, files: &[(PathBuf, FileInfo)], started_at: i64) -> Self {
+        let mut entry = Self::new(Direction::Sent, result.session_id.clone());
+        entry.started_at = started_at;
+        if let Some(peer) = &result.peer {
+            entry.peer_alias = peer.alias.clone();
+            entry.peer_fingerprint = peer.fingerprint.clone();
+        } else {
+            entry.peer_alias = result.target.to_string();
+        }
+        entry.error = result.error.clone();
+        entry.files = files
+            .iter()
+            .map(|(path, info)| HistoryFile {
+                id: info.id.clone(),
+                file_name: info.file_name.clone(),
+                size: info.size,
+                path: Some(path.clone()),
+                sha256: info.sha256.clone(),
+                error: match result.failed.get(&info.id) {
+                    Some(e) => Some(e.clone()),
+                    None if !result.accepted.contains(&info.id) => Some("not accepted".to_string()),
+                    None => None,
+                },
+            })
+            .collect();
+        if result.declined {
+            entry.outcome = Outcome::Declined;
+        }
+        entry.finish(None);
+        entry
+    }
+
+    // 计算结束时间和结果，`outcome` 为 None 时按文件是否失败判断
+    pub fn finish(&mut self, outcome: Option<Outcome>) {
+        self.duration_ms = now_millis().saturating_sub(self.started_at).max(0) as u64;
+        if let Some(outcome) = outcome {
+            self.outcome = outcome;
+        } else if self.outcome != Outcome::Declined {
+            self.outcome = self.outcome_from_files();
+        }
+    }
+
+    fn outcome_from_files(&self) -> Outcome {
+        if self.error.is_some() {
+            return Outcome::Failed;
+        }
+        match self.files.iter().filter(|f| f.error.is_some()).count() {
+            0 => Outcome::Completed,
+            n if n == self.files.len() => Outcome::Failed,
+            _ => Outcome::PartiallyFailed,
+        }
+    }
+
+    // 补全没有 hash 的已完成文件，文件较大时比较耗时
+    pub fn fill_hashes(&mut self) {
+        for file in &mut self.files {
+            if let (None, None, Some(path)) = (&file.sha256, &file.error, &file.path) {
+                file.sha256 = sha256_file(path).ok();
+            }
+        }
+    }
+
+    fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        self.peer_alias.to_lowercase().contains(&query)
+            || self.peer_fingerprint.to_lowercase().contains(&query)
+            || self
+                .files
+                .iter()
+                .any(|f| f.file_name.to_lowercase().contains(&query))
+    }
+}
+
+pub fn now_millis() -> i64 {
+    chrono::Local::now().timestamp_millis()
+}
+
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+// 每行一条 JSON 记录，追加写入
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn append(&self, entry: &HistoryEntry) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(entry)?)
+    }
+
+    // 最新的记录在前，跳过损坏的行
+    pub fn list(&self) -> io::Result<Vec<HistoryEntry>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut entries = vec![];
+        for line in BufReader::new(file).lines() {
+            match serde_json::from_str(&line?) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => log::warn!("skip broken history entry: {e}"),
+            }
+        }
+        entries.reverse();
+        Ok(entries)
+    }
+
+    // 按设备名、指纹或文件名搜索，不区分大小写
+    pub fn search(&self, query: &str) -> io::Result<Vec<HistoryEntry>> {
+        let mut entries = self.list()?;
+        entries.retain(|entry| entry.matches(query));
+        Ok(entries)
+    }
+
+    pub fn clear(&self) -> io::Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn export_json(entries: &[HistoryEntry]) -> String {
+    serde_json::to_string_pretty(entries).unwrap_or_default()
+}
+
+// 每个文件一行
+pub fn export_csv(entries: &[HistoryEntry]) -> String {
+    let mut csv = String::from(
+        "started_at,direction,peer_alias,peer_fingerprint,outcome,duration_ms,file_name,size,path,sha256,error\n",
+    );
+    for entry in entries {
+        let started_at = chrono::DateTime::from_timestamp_millis(entry.started_at)
+            .map(|t| t.with_timezone(&chrono::Local).to_rfc3339())
+            .unwrap_or_default();
+        for file in &entry.files {
+            let row = [
+                started_at.clone(),
+                format!("{:?}", entry.direction).to_lowercase(),
+                entry.peer_alias.clone(),
+                entry.peer_fingerprint.clone(),
+                format!("{:?}", entry.outcome),
+                entry.duration_ms.to_string(),
+                file.file_name.clone(),
+                file.size.to_string(),
+                file.path
+                    .as_ref()
+                    .map(|p| p.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+                file.sha256.clone().unwrap_or_default(),
+                file.error
+                    .clone()
+                    .or(entry.error.clone())
+                    .unwrap_or_default(),
+            ];
+            let row = row.iter().map(|field| csv_field(field)).collect::<Vec<_>>();
+            csv.push_str(&row.join(","));
+            csv.push('\n');
+        }
+    }
+    csv
+}
+
+fn csv_field(field: &str) -> String {
+    // 设备名和文件名由对方提供，表格软件会把这些字符开头的内容当作公式
+    let field = match field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        true => format!("'{field}"),
+        false => field.to_string(),
+    };
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history() {
+        let dir = std::env::temp_dir().join(format!("history-{}", uuid::Uuid::new_v4()));
+        let history = History::new(dir.join("history.jsonl"));
+        assert!(history.list().unwrap().is_empty());
+        fs::create_dir_all(&dir).unwrap();
+
+        let file = FileInfo {
+            id: "1".to_string(),
+            file_name: "report, final.pdf".to_string(),
+            size: 3,
+            ..Default::default()
+        };
+        let mut mission = crate::mission::Mission::new(
+            [(file.id.clone(), file)].into(),
+            crate::model::DeviceMessage {
+                alias: "Alice".to_string(),
+                fingerprint: "fp-alice".to_string(),
+                ..Default::default()
+            },
+        );
+        let path = dir.join("report.pdf");
+        fs::write(&path, "abc").unwrap();
+        assert!(mission.finish_file("1", Ok(path)));
+        let mut entry = HistoryEntry::received(&mission.summary(mission.state));
+        entry.fill_hashes();
+        assert_eq!(entry.outcome, Outcome::Completed);
+        assert_eq!(
+            entry.files[0].sha256.as_deref(),
+            Some("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        history.append(&entry).unwrap();
+
+        assert_eq!(history.search("alice").unwrap().len(), 1);
+        assert_eq!(history.search("FINAL").unwrap().len(), 1);
+        assert!(history.search("bob").unwrap().is_empty());
+        let csv = export_csv(&history.list().unwrap());
+        assert!(csv
+            .lines()
+            .nth(1)
+            .unwrap()
+            .contains("\"report, final.pdf\""));
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("@alice"), "'@alice");
+        assert_eq!(csv_field("a-b"), "a-b");
+
+        history.clear().unwrap();
+        assert!(history.list().unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
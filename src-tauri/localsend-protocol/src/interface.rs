@@ -0,0 +1,196 @@
+use std::{net::Ipv4Addr, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+// 默认排除的虚拟网卡（容器网桥、虚拟机、VPN 隧道）
+pub const DEFAULT_EXCLUDED_INTERFACES: &[&str] = &[
+    "docker*", "veth*", "br-*", "virbr*", "vmnet*", "vboxnet*", "tun*", "tap*", "utun*", "wg*",
+    "zt*",
+];
+
+pub fn default_excluded_interfaces() -> Vec<String> {
+    DEFAULT_EXCLUDED_INTERFACES
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkInterface {
+    pub name: String,
+    pub ip: Ipv4Addr,
+}
+
+// 网卡匹配规则：网卡名（支持 `*` 通配）、单个 IP 或 CIDR
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterfaceMatcher {
+    Name(String),
+    Addr(Ipv4Addr),
+    Cidr(Ipv4Addr, u8),
+}
+
+impl FromStr for InterfaceMatcher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("empty interface pattern".to_string());
+        }
+        if let Some((ip, prefix)) = s.split_once('/') {
+            let ip = ip
+                .parse::<Ipv4Addr>()
+                .map_err(|e| format!("invalid CIDR `{s}`: {e}"))?;
+            let prefix = prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= 32)
+                .ok_or(format!("invalid CIDR prefix `{s}`"))?;
+            return Ok(Self::Cidr(ip, prefix));
+        }
+        match s.parse::<Ipv4Addr>() {
+            Ok(ip) => Ok(Self::Addr(ip)),
+            Err(_) => Ok(Self::Name(s.to_string())),
+        }
+    }
+}
+
+impl InterfaceMatcher {
+    pub fn matches(&self, iface: &NetworkInterface) -> bool {
+        match self {
+            Self::Name(pattern) => wildcard_match(pattern, &iface.name),
+            Self::Addr(ip) => *ip == iface.ip,
+            Self::Cidr(net, prefix) => {
+                let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                u32::from(*net) & mask == u32::from(iface.ip) & mask
+            }
+        }
+    }
+}
+
+// 只支持 `*` 的简单通配
+pub(crate) fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts = parts.collect::<Vec<&str>>();
+    let Some((last, middle)) = parts.split_last() else {
+        // 没有 `*`，需要完全相同
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+pub fn parse_matchers(patterns: &[String]) -> Vec<InterfaceMatcher> {
+    patterns
+        .iter()
+        .filter_map(|p| match p.parse() {
+            Ok(m) => Some(m),
+            Err(e) => {
+                log::warn!("ignore interface pattern: {e}");
+                None
+            }
+        })
+        .collect()
+}
+
+// 本机所有非回环的 IPv4 网卡
+pub fn list_interfaces() -> Vec<NetworkInterface> {
+    if_addrs::get_if_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|iface| match iface.addr {
+            if_addrs::IfAddr::V4(v4) if !v4.is_loopback() => Some(NetworkInterface {
+                name: iface.name,
+                ip: v4.ip,
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+// `include` 为空时选择全部网卡，`exclude` 优先
+pub fn filter_interfaces(
+    interfaces: Vec<NetworkInterface>,
+    include: &[String],
+    exclude: &[String],
+) -> Vec<NetworkInterface> {
+    let include = parse_matchers(include);
+    let exclude = parse_matchers(exclude);
+    interfaces
+        .into_iter()
+        .filter(|iface| include.is_empty() || include.iter().any(|m| m.matches(iface)))
+        .filter(|iface| !exclude.iter().any(|m| m.matches(iface)))
+        .collect()
+}
+
+pub fn select_interfaces(include: &[String], exclude: &[String]) -> Vec<NetworkInterface> {
+    filter_interfaces(list_interfaces(), include, exclude)
+}
+
+// 解析 HTTP 服务的绑定地址：`0.0.0.0`、IP、网卡名或 CIDR
+pub fn resolve_bind_addr(spec: &str) -> Option<Ipv4Addr> {
+    match spec.parse::<InterfaceMatcher>().ok()? {
+        InterfaceMatcher::Addr(ip) => Some(ip),
+        matcher => list_interfaces()
+            .into_iter()
+            .find(|iface| matcher.matches(iface))
+            .map(|iface| iface.ip),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iface(name: &str, ip: &str) -> NetworkInterface {
+        NetworkInterface {
+            name: name.to_string(),
+            ip: ip.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_matcher() {
+        let eth = iface("eth0", "192.168.1.23");
+        assert!("eth0".parse::<InterfaceMatcher>().unwrap().matches(&eth));
+        assert!("eth*".parse::<InterfaceMatcher>().unwrap().matches(&eth));
+        assert!(!"veth*".parse::<InterfaceMatcher>().unwrap().matches(&eth));
+        assert!("192.168.1.0/24"
+            .parse::<InterfaceMatcher>()
+            .unwrap()
+            .matches(&eth));
+        assert!(!"192.168.2.0/24"
+            .parse::<InterfaceMatcher>()
+            .unwrap()
+            .matches(&eth));
+        assert!("0.0.0.0/0"
+            .parse::<InterfaceMatcher>()
+            .unwrap()
+            .matches(&eth));
+        assert!("192.168.1.0/33".parse::<InterfaceMatcher>().is_err());
+    }
+
+    #[test]
+    fn test_filter_default_excludes_virtual() {
+        let interfaces = vec![
+            iface("en0", "192.168.1.23"),
+            iface("docker0", "172.17.0.1"),
+            iface("veth12ab", "172.17.0.5"),
+            iface("tun0", "10.8.0.2"),
+        ];
+        let selected = filter_interfaces(interfaces.clone(), &[], &default_excluded_interfaces());
+        assert_eq!(selected, vec![iface("en0", "192.168.1.23")]);
+
+        let selected = filter_interfaces(interfaces, &["10.0.0.0/8".to_string()], &[]);
+        assert_eq!(selected, vec![iface("tun0", "10.8.0.2")]);
+    }
+}
@@ -1,6 +1,18 @@
 pub mod api;
+pub mod event;
+pub mod guard;
+pub mod history;
+pub mod interface;
+pub mod mime;
 pub mod mission;
 pub mod model;
 pub mod multicast;
+pub mod policy;
+pub mod preview;
 pub mod request;
+pub mod route;
+pub mod sender;
 pub mod server;
+pub mod share;
+pub mod throttle;
+pub mod v1;
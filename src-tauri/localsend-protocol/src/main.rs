@@ -1,65 +1,865 @@
-use std::{collections::HashSet, net::SocketAddr, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
-use tokio::sync::mpsc;
+use clap::{Parser, Subcommand};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader, Lines, Stdin},
+    sync::{mpsc, oneshot, Mutex, RwLock},
+    time,
+};
 
 use localsend_protocol::{
-    model::DeviceType,
-    request::send_register,
-    server::{Server, ServerMessage, ServerSetting},
+    event::{self, Event},
+    mission::MissionState,
+    model::{collect_files, DeviceMessage, DeviceType, FileInfo},
+    policy::AcceptPolicy,
+    preview::{ffmpeg_hook, PreviewGenerator},
+    request::{cancel, info, register, send_register, send_text},
+    sender::{send_to_many, SendEvent},
+    server::{OutMessage, Server, ServerMessage, ServerSetting},
+    share::Share,
+    throttle::global_throttle,
 };
 use uuid::Uuid;
 
-// for test
+type Error = Box<dyn std::error::Error>;
+
+// 多个询问共用标准输入，依次进行
+type Prompt = Arc<Mutex<Lines<BufReader<Stdin>>>>;
+
+const DEFAULT_PORT: u16 = 53317;
+
+#[derive(Parser)]
+#[command(name = "localsend-cli", version, about = "LocalSend 命令行客户端")]
+struct Cli {
+    /// 设备名
+    #[arg(long, default_value = "localsend-cli")]
+    alias: String,
+    /// HTTP 服务端口，被占用时向后尝试
+    #[arg(long, default_value_t = DEFAULT_PORT)]
+    port: u16,
+    /// HTTP 服务绑定的地址：IP、网卡名或 CIDR
+    #[arg(long, default_value = "0.0.0.0")]
+    bind: String,
+    /// 收发组播消息的网卡（网卡名或 CIDR），可多次指定
+    #[arg(long = "interface")]
+    interfaces: Vec<String>,
+    /// 发送和接收合计的带宽上限（字节/秒）
+    #[arg(long)]
+    bandwidth_limit: Option<u64>,
+    /// 输出调试日志
+    #[arg(short, long)]
+    verbose: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// 接收文件
+    Receive {
+        /// 文件保存目录
+        #[arg(short, long, default_value = ".")]
+        store: PathBuf,
+        /// 自动同意所有文件传入请求
+        #[arg(short = 'y', long, conflicts_with = "policy")]
+        auto_accept: bool,
+        /// 自动同意/拒绝规则的 JSON 文件
+        #[arg(long)]
+        policy: Option<PathBuf>,
+        /// 按发送方和文件类型选择保存目录的规则（JSON 文件）
+        #[arg(long)]
+        routes: Option<PathBuf>,
+        /// 发送方需要提供的 PIN
+        #[arg(long)]
+        pin: Option<String>,
+        /// 以 JSON 行输出事件，并从标准输入读取 JSON 命令
+        #[arg(long)]
+        json: bool,
+        /// 保存接收任务的文件，重启后发送方可以续传
+        #[arg(long)]
+        session_file: Option<PathBuf>,
+        /// 接收任务空闲多久后过期（秒）
+        #[arg(long, default_value_t = 3600)]
+        session_timeout: u64,
+        /// 接收的单个文件大小上限（字节）
+        #[arg(long)]
+        max_file_size: Option<u64>,
+        /// 一次接收的文件总大小上限（字节）
+        #[arg(long)]
+        max_session_size: Option<u64>,
+        /// 接收后保存目录所在磁盘至少剩余的空间（字节）
+        #[arg(long, default_value_t = 64 * 1024 * 1024)]
+        disk_space_margin: u64,
+    },
+    /// 发送文件或文件夹到设备名、指纹或 IP[:端口]，多个设备用逗号分隔
+    Send {
+        target: String,
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// 接收方要求的 PIN
+        #[arg(long)]
+        pin: Option<String>,
+        /// 按设备名查找时的扫描时间（秒）
+        #[arg(long, default_value_t = 3)]
+        timeout: u64,
+        /// 用 ffmpeg 截取视频第一帧作为预览
+        #[arg(long)]
+        video_preview: bool,
+        /// 每个设备同时上传的文件数
+        #[arg(long, default_value_t = 4)]
+        parallel: usize,
+        /// 连接中断等临时错误的重试次数
+        #[arg(long, default_value_t = 3)]
+        retries: u32,
+    },
+    /// 发送文本消息
+    Text {
+        target: String,
+        text: String,
+        /// 接收方要求的 PIN
+        #[arg(long)]
+        pin: Option<String>,
+        /// 按设备名查找时的扫描时间（秒）
+        #[arg(long, default_value_t = 3)]
+        timeout: u64,
+    },
+    /// 通过网页分享文件或文件夹，对方用浏览器打开链接下载
+    Share {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// 没有链接时访问需要的 PIN
+        #[arg(long)]
+        pin: Option<String>,
+        /// 不需要同意浏览器的访问
+        #[arg(short = 'y', long)]
+        auto_accept: bool,
+    },
+    /// 扫描局域网内的设备
+    Scan {
+        /// 扫描时间（秒）
+        #[arg(long, default_value_t = 3)]
+        timeout: u64,
+    },
+    /// 获取某个设备的信息
+    Info { target: String },
+}
+
 #[tokio::main]
 async fn main() {
-    let _ = env_logger::builder()
-        .filter_level(log::LevelFilter::Info)
-        .try_init();
-    let (_out_tx, out_rx) = mpsc::channel(8);
+    let cli = Cli::parse();
+    let level = match cli.verbose {
+        true => log::LevelFilter::Info,
+        false => log::LevelFilter::Warn,
+    };
+    let _ = env_logger::builder().filter_level(level).try_init();
+
+    if let Err(e) = run(cli).await {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), Error> {
     let setting = ServerSetting {
-        alias: "test_device".to_string(),
-        device_model: Some("test_model".to_string()),
+        alias: cli.alias,
+        device_model: Some("cli".to_string()),
         device_type: Some(DeviceType::Headless),
-        protocol: Some(localsend_protocol::model::Protocol::Http),
-        download: false,
-        port: 53317,
-        store_path: PathBuf::from("/Users/cakeal/Downloads"),
+        port: cli.port,
+        interface_addr: cli.bind,
+        interfaces: cli.interfaces,
         fingerprint: Uuid::new_v4().to_string(),
+        bandwidth_limit: cli.bandwidth_limit,
         ..Default::default()
     };
+    if setting.bandwidth_limit == Some(0) {
+        return Err("--bandwidth-limit must not be 0".into());
+    }
+    global_throttle().set_limit(setting.bandwidth_limit);
+    match cli.command {
+        Command::Receive {
+            store,
+            auto_accept,
+            policy,
+            routes,
+            pin,
+            json,
+            session_file,
+            session_timeout,
+            max_file_size,
+            max_session_size,
+            disk_space_margin,
+        } => {
+            let accept_policy = match (auto_accept, policy) {
+                (true, _) => AcceptPolicy::accept_all(),
+                (false, Some(path)) => serde_json::from_str(&std::fs::read_to_string(path)?)?,
+                (false, None) => AcceptPolicy::default(),
+            };
+            let routes = match routes {
+                Some(path) => serde_json::from_str(&std::fs::read_to_string(path)?)?,
+                None => vec![],
+            };
+            let setting = ServerSetting {
+                store_path: store,
+                accept_policy,
+                routes,
+                pin,
+                session_file,
+                session_timeout,
+                max_file_size,
+                max_session_size,
+                disk_space_margin,
+                ..setting
+            };
+            setting.validate()?;
+            match json {
+                true => receive_json(setting).await,
+                false => receive(setting).await,
+            }
+        }
+        Command::Send {
+            target,
+            files,
+            pin,
+            timeout,
+            video_preview,
+            parallel,
+            retries,
+        } => {
+            if parallel == 0 {
+                return Err("--parallel must not be 0".into());
+            }
+            let setting = ServerSetting {
+                max_parallel_uploads: parallel,
+                upload_retries: retries,
+                ..setting
+            };
+            let targets = target.split(',').map(str::to_string).collect::<Vec<_>>();
+            let targets = resolve_targets(&setting, &targets, Duration::from_secs(timeout)).await?;
+            let preview = match video_preview {
+                true => PreviewGenerator::default().with_video_hook(ffmpeg_hook()),
+                false => PreviewGenerator::default(),
+            };
+            send(&setting, targets, files, pin.as_deref(), &preview).await
+        }
+        Command::Text {
+            target,
+            text,
+            pin,
+            timeout,
+        } => {
+            let addr = resolve_target(&setting, &target, Duration::from_secs(timeout)).await?;
+            // 接收方只接受已注册设备的请求
+            if let Err(e) = register(&setting, &addr).await {
+                log::warn!("register to {addr} error: {e}");
+            }
+            send_text(&setting, &text, &addr, pin.as_deref()).await
+        }
+        Command::Share {
+            files,
+            pin,
+            auto_accept,
+        } => {
+            let mut shared = vec![];
+            for path in files {
+                shared.extend(collect_files(&path)?);
+            }
+            share(setting, Share::new(shared, pin, auto_accept)).await
+        }
+        Command::Scan { timeout } => {
+            let devices = scan(&setting, Duration::from_secs(timeout)).await?;
+            if devices.is_empty() {
+                println!("no device found");
+            }
+            for (addr, device) in devices {
+                print_device(&addr, &device);
+            }
+            Ok(())
+        }
+        Command::Info { target } => {
+            let addr = parse_addr(&target).ok_or(format!("invalid address `{target}`"))?;
+            // 旧版本没有 info 接口，退回到 register
+            let device = match info(&addr).await {
+                Ok(device) => device,
+                Err(_) => register(&setting, &addr).await?,
+            };
+            print_device(&addr, &device);
+            Ok(())
+        }
+    }
+}
+
+fn parse_addr(target: &str) -> Option<SocketAddr> {
+    target.parse::<SocketAddr>().ok().or_else(|| {
+        Some(SocketAddr::new(
+            target.parse::<IpAddr>().ok()?,
+            DEFAULT_PORT,
+        ))
+    })
+}
+
+fn print_device(addr: &SocketAddr, device: &DeviceMessage) {
+    println!(
+        "{}\t{}\t{:?}\t{}\t{}",
+        device.alias,
+        addr,
+        device.device_type,
+        device.device_model.as_deref().unwrap_or_default(),
+        device.fingerprint
+    );
+}
+
+fn progress_style() -> ProgressStyle {
+    ProgressStyle::with_template("{msg:30!} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec} {eta}")
+        .unwrap()
+        .progress_chars("=> ")
+}
+
+// 启动一个临时服务器，收集 `timeout` 时间内发现的设备
+async fn scan(
+    setting: &ServerSetting,
+    timeout: Duration,
+) -> Result<Vec<(SocketAddr, DeviceMessage)>, Error> {
+    let (_out_tx, out_rx) = mpsc::channel(8);
     let (server, mut server_rx) = Server::new(setting.clone(), out_rx);
-    let _ = tokio::spawn(async move {
-        loop {
-            if let Some(message) = server_rx.recv().await {
-                match message {
-                    // 服务器监听到连接请求
-                    ServerMessage::DeviceConnect(addr, _device) => {
-                        let addr = SocketAddr::new(addr.ip(), setting.port);
-                        if let Err(e) = send_register(&setting, &addr).await {
-                            log::error!("send register error: {e:?}");
+    let handle = server.handle();
+    let task = tokio::spawn(async move { server.start().await.map_err(|e| e.to_string()) });
+
+    let mut devices = HashMap::new();
+    let deadline = time::Instant::now() + timeout;
+    loop {
+        match time::timeout_at(deadline, server_rx.recv()).await {
+            Ok(Some(ServerMessage::DeviceConnect(addr, device))) => {
+                if device.fingerprint == setting.fingerprint {
+                    continue;
+                }
+                let addr = SocketAddr::new(addr.ip(), device.port.unwrap_or(DEFAULT_PORT));
+                devices.insert(device.fingerprint.clone(), (addr, device));
+            }
+            Ok(Some(_)) => {}
+            Ok(None) | Err(_) => break,
+        }
+    }
+    handle.shutdown(None).await;
+    task.await??;
+    Ok(devices.into_values().collect())
+}
+
+async fn resolve_target(
+    setting: &ServerSetting,
+    target: &str,
+    timeout: Duration,
+) -> Result<SocketAddr, Error> {
+    let targets = resolve_targets(setting, &[target.to_string()], timeout).await?;
+    Ok(targets[0])
+}
+
+// 有设备名或指纹时只扫描一次，重复的设备只保留一个
+async fn resolve_targets(
+    setting: &ServerSetting,
+    targets: &[String],
+    timeout: Duration,
+) -> Result<Vec<SocketAddr>, Error> {
+    let devices = match targets.iter().all(|t| parse_addr(t).is_some()) {
+        true => vec![],
+        false => scan(setting, timeout).await?,
+    };
+    let mut addrs = vec![];
+    for target in targets {
+        let addr = match parse_addr(target) {
+            Some(addr) => addr,
+            None => devices
+                .iter()
+                .find(|(_, device)| device.alias == *target || device.fingerprint == *target)
+                .map(|(addr, _)| *addr)
+                .ok_or(format!("device `{target}` not found"))?,
+        };
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    Ok(addrs)
+}
+
+async fn send(
+    setting: &ServerSetting,
+    targets: Vec<SocketAddr>,
+    paths: Vec<PathBuf>,
+    pin: Option<&str>,
+    preview: &PreviewGenerator,
+) -> Result<(), Error> {
+    let mut files = vec![];
+    for path in paths {
+        for (path, mut file_info) in collect_files(&path)? {
+            preview.fill(&mut file_info, &path);
+            files.push((path, file_info));
+        }
+    }
+    let sizes = files
+        .iter()
+        .map(|(_, f)| (f.id.clone(), (f.file_name.clone(), f.size)))
+        .collect::<HashMap<_, _>>();
+    let multi_target = targets.len() > 1;
+    for target in &targets {
+        println!("waiting for {target} to accept...");
+    }
+    let (events_tx, mut events_rx) = mpsc::channel(64);
+    let task = tokio::spawn(send_to_many(
+        setting.clone(),
+        targets,
+        files,
+        pin.map(str::to_string),
+        events_tx,
+    ));
+
+    let multi = MultiProgress::new();
+    let mut bars = HashMap::new();
+    let mut sessions = vec![];
+    loop {
+        tokio::select! {
+            event = events_rx.recv() => match event {
+                Some(SendEvent::Accepted { target, session_id, file_ids }) => {
+                    sessions.push((target, session_id));
+                    for id in file_ids {
+                        let Some((name, size)) = sizes.get(&id) else {
+                            continue;
+                        };
+                        let bar = multi.add(ProgressBar::new(*size));
+                        bar.set_style(progress_style());
+                        bar.set_message(match multi_target {
+                            true => format!("{target} {name}"),
+                            false => name.clone(),
+                        });
+                        bars.insert((target, id), bar);
+                    }
+                }
+                Some(SendEvent::Progress { target, file_id, bytes }) => {
+                    if let Some(bar) = bars.get(&(target, file_id)) {
+                        bar.set_position(bytes as u64);
+                    }
+                }
+                Some(SendEvent::Retrying { target, file_id, attempt, error }) => {
+                    if let Some(bar) = bars.get(&(target, file_id)) {
+                        bar.set_position(0);
+                        multi.suspend(|| println!("{}: {error}, retry #{attempt}", bar.message()));
+                    }
+                }
+                Some(SendEvent::FileFinished { target, file_id, error }) => {
+                    if let Some(bar) = bars.get(&(target, file_id)) {
+                        match error {
+                            None => bar.finish(),
+                            Some(e) => bar.abandon_with_message(format!("{}: {e}", bar.message())),
                         }
                     }
-                    ServerMessage::FilePrepareUpload(file_req, agreed_tx) => {
-                        // 模拟全部同意
-                        let agreed_ids = file_req
-                            .files
-                            .into_keys()
-                            .map(|file_id| file_id)
-                            .collect::<HashSet<String>>();
-                        let _ = agreed_tx.send(agreed_ids);
+                }
+                Some(SendEvent::Finished { result }) => match result.error {
+                    Some(e) if multi_target || result.declined => {
+                        multi.suspend(|| println!("{}: {e}", result.target));
+                    }
+                    _ => {}
+                },
+                None => break,
+            },
+            _ = tokio::signal::ctrl_c() => {
+                for (target, session_id) in sessions {
+                    if let Err(e) = cancel(session_id, &target).await {
+                        log::warn!("cancel {target} error: {e}");
                     }
-                    ServerMessage::Progress(file_id, mut rx) => {
+                }
+                return Err("cancelled".into());
+            }
+        }
+    }
+
+    let results = task.await?;
+    let failed = results.iter().filter(|r| !r.is_success()).count();
+    match (failed, &results[..]) {
+        (0, _) => Ok(()),
+        (_, [result]) if result.declined => Ok(()),
+        (_, [result]) => match &result.error {
+            Some(e) => Err(e.clone().into()),
+            None => Err(format!("{} file(s) failed", result.failed.len()).into()),
+        },
+        (n, results) => Err(format!("{n} of {} device(s) failed", results.len()).into()),
+    }
+}
+
+fn prompt() -> Prompt {
+    Arc::new(Mutex::new(BufReader::new(tokio::io::stdin()).lines()))
+}
+
+// 打印问题并读取一行回答，等待前面的询问结束后才开始
+async fn ask(prompt: &Prompt, question: String) -> String {
+    let mut stdin = prompt.lock().await;
+    println!("{question}");
+    stdin.next_line().await.ok().flatten().unwrap_or_default()
+}
+
+async fn receive(setting: ServerSetting) -> Result<(), Error> {
+    let (out_tx, out_rx) = mpsc::channel(8);
+    let (server, mut server_rx) = Server::new(setting.clone(), out_rx);
+    let setting = Arc::new(RwLock::new(setting));
+    shutdown_on_ctrl_c(out_tx.clone());
+    tokio::spawn(async move {
+        let multi = MultiProgress::new();
+        let prompt = prompt();
+        let mut files: HashMap<String, FileInfo> = HashMap::new();
+        while let Some(message) = server_rx.recv().await {
+            match message {
+                ServerMessage::Listening(addr) => {
+                    let mut setting = setting.write().await;
+                    setting.bound_port = Some(addr.port());
+                    println!("receiving as `{}` on {addr}", setting.alias);
+                }
+                // 服务器监听到连接请求
+                ServerMessage::DeviceConnect(addr, device) => {
+                    let addr = SocketAddr::new(addr.ip(), device.port.unwrap_or(DEFAULT_PORT));
+                    let setting = setting.read().await.clone();
+                    if let Err(e) = send_register(&setting, &addr).await {
+                        log::error!("send register error: {e:?}");
+                    }
+                }
+                // 在单独的任务中等待回答，期间继续处理其他消息
+                ServerMessage::FilePrepareUpload(file_req, free_space, agreed_tx) => {
+                    files.extend(file_req.files.clone());
+                    let (prompt, setting, out_tx) =
+                        (prompt.clone(), setting.clone(), out_tx.clone());
+                    tokio::spawn(async move {
+                        let mut question = format!(
+                            "{} wants to send {} file(s):\n",
+                            file_req.info.alias,
+                            file_req.files.len()
+                        );
+                        for file in file_req.files.values() {
+                            question += &format!("  {} ({} bytes)\n", file.file_name, file.size);
+                        }
+                        if let Some(free_space) = free_space {
+                            let store_path = setting.read().await.store_path.clone();
+                            question += &format!("{free_space} bytes free in {store_path:?}\n");
+                        }
+                        question += "accept? [y/N/b(lock)]";
+                        let answer = ask(&prompt, question).await;
+                        let agreed_ids = match answer.trim().to_lowercase().as_str() {
+                            "y" | "yes" => file_req.files.keys().cloned().collect(),
+                            "b" | "block" => {
+                                let mut setting = setting.write().await;
+                                setting
+                                    .blocked_devices
+                                    .push(file_req.info.fingerprint.clone());
+                                let _ = out_tx
+                                    .send(OutMessage::UpdateSettings(setting.clone()))
+                                    .await;
+                                println!("blocked {}", file_req.info.alias);
+                                HashSet::new()
+                            }
+                            _ => HashSet::new(),
+                        };
+                        let _ = agreed_tx.send(agreed_ids);
+                    });
+                }
+                ServerMessage::FileAutoAccepted(file_req) => {
+                    println!(
+                        "accepted {} file(s) from {}",
+                        file_req.files.len(),
+                        file_req.info.alias
+                    );
+                    files.extend(file_req.files);
+                }
+                ServerMessage::FileAutoDeclined(file_req) => {
+                    println!(
+                        "declined {} file(s) from {}",
+                        file_req.files.len(),
+                        file_req.info.alias
+                    );
+                }
+                ServerMessage::FileDeclined(file_req, reason) => {
+                    println!("declined files from {}: {reason}", file_req.info.alias);
+                }
+                ServerMessage::Progress(file_id, mut rx) => {
+                    let file = files.remove(&file_id).unwrap_or_default();
+                    let bar = multi.add(ProgressBar::new(file.size));
+                    bar.set_style(progress_style());
+                    bar.set_message(file.file_name);
+                    tokio::spawn(async move {
                         while rx.changed().await.is_ok() {
-                            println!("file_id: {file_id}, rx: {}", *rx.borrow());
+                            bar.set_position(*rx.borrow() as u64);
+                        }
+                        bar.finish();
+                    });
+                }
+                ServerMessage::CancelMission(mission) => {
+                    if let Some(mission) = mission {
+                        println!("{} cancelled the transfer", mission.sender_device.alias);
+                    }
+                }
+                ServerMessage::MissionStarted(_) => {}
+                ServerMessage::TextReceived(sender, text) => {
+                    println!("{}: {text}", sender.alias);
+                }
+                ServerMessage::UploadFinished(_, file_id, Err(e)) => {
+                    println!("failed to receive {file_id}: {e}");
+                }
+                ServerMessage::UploadFinished(..) => {}
+                ServerMessage::MissionFinished(summary) => {
+                    let alias = &summary.sender_device.alias;
+                    match summary.state {
+                        MissionState::Completed => {
+                            println!("received {} file(s) from {alias}", summary.files.len());
+                        }
+                        MissionState::Expired => println!("transfer from {alias} expired"),
+                        _ => {}
+                    }
+                }
+                ServerMessage::ShareAccess(..) => {}
+            }
+        }
+    });
+    server.start().await
+}
+
+async fn share(setting: ServerSetting, share: Share) -> Result<(), Error> {
+    let (out_tx, out_rx) = mpsc::channel(8);
+    let (server, mut server_rx) = Server::new(setting.clone(), out_rx);
+    shutdown_on_ctrl_c(out_tx.clone());
+    println!("sharing {} file(s)", share.files.len());
+    out_tx.send(OutMessage::Share(Some(share.clone()))).await?;
+    tokio::spawn(async move {
+        let prompt = prompt();
+        while let Some(message) = server_rx.recv().await {
+            match message {
+                // 绑定所有地址时列出每个网卡的链接
+                ServerMessage::Listening(addr) => {
+                    let ips = match addr.ip().is_unspecified() {
+                        true => setting
+                            .multicast_interfaces()
+                            .into_iter()
+                            .map(IpAddr::V4)
+                            .collect(),
+                        false => vec![addr.ip()],
+                    };
+                    for ip in ips {
+                        println!("{}", share.link(SocketAddr::new(ip, addr.port())));
+                    }
+                    if let Some(pin) = &share.pin {
+                        println!("or open http://<ip>:{} with PIN {pin}", addr.port());
+                    }
+                }
+                ServerMessage::ShareAccess(ip, user_agent, allow_tx) => {
+                    let prompt = prompt.clone();
+                    tokio::spawn(async move {
+                        let question = format!(
+                            "{ip} ({}) wants to access shared files\nallow? [y/N]",
+                            user_agent.as_deref().unwrap_or("unknown")
+                        );
+                        let answer = ask(&prompt, question).await;
+                        let _ = allow_tx
+                            .send(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"));
+                    });
+                }
+                _ => {}
+            }
+        }
+    });
+    server.start().await
+}
+
+fn shutdown_on_ctrl_c(out_tx: mpsc::Sender<OutMessage>) {
+    tokio::spawn(async move {
+        let _ = tokio::signal::ctrl_c().await;
+        let _ = out_tx
+            .send(OutMessage::Shutdown(Some(Duration::from_secs(5))))
+            .await;
+    });
+}
+
+fn emit(event: Event) {
+    match serde_json::to_string(&event) {
+        Ok(line) => println!("{line}"),
+        Err(e) => log::error!("serialize event error: {e}"),
+    }
+}
+
+// 每行输出一个 JSON 事件，从标准输入按行读取 JSON 命令
+async fn receive_json(setting: ServerSetting) -> Result<(), Error> {
+    let (out_tx, out_rx) = mpsc::channel(8);
+    let (server, mut server_rx) = Server::new(setting.clone(), out_rx);
+    let mut setting = setting;
+    shutdown_on_ctrl_c(out_tx.clone());
+    tokio::spawn(async move {
+        let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+        let mut stdin_open = true;
+        // 等待 `accept`/`decline` 的请求：请求 id -> (全部文件 id, 回复)
+        let mut pending: HashMap<String, (HashSet<String>, oneshot::Sender<HashSet<String>>)> =
+            HashMap::new();
+        loop {
+            tokio::select! {
+                message = server_rx.recv() => {
+                    let Some(message) = message else {
+                        break;
+                    };
+                    match message {
+                        ServerMessage::Listening(addr) => {
+                            setting.bound_port = Some(addr.port());
+                            emit(Event::Listening { addr });
+                        }
+                        ServerMessage::DeviceConnect(addr, device) => {
+                            let reply_addr =
+                                SocketAddr::new(addr.ip(), device.port.unwrap_or(DEFAULT_PORT));
+                            if let Err(e) = send_register(&setting, &reply_addr).await {
+                                emit(Event::Error {
+                                    message: format!("send register to {reply_addr} error: {e}"),
+                                });
+                            }
+                            emit(Event::DeviceConnect { addr, device });
+                        }
+                        ServerMessage::FilePrepareUpload(request, free_space, agreed_tx) => {
+                            drop_closed(&mut pending);
+                            let request_id = Uuid::new_v4().to_string();
+                            let file_ids = request.files.keys().cloned().collect();
+                            pending.insert(request_id.clone(), (file_ids, agreed_tx));
+                            emit(Event::PrepareUpload {
+                                request_id,
+                                request,
+                                free_space,
+                            });
+                        }
+                        ServerMessage::FileAutoAccepted(request) => {
+                            emit(Event::AutoAccepted { request });
+                        }
+                        ServerMessage::FileAutoDeclined(request) => {
+                            emit(Event::AutoDeclined { request });
+                        }
+                        ServerMessage::FileDeclined(request, reason) => {
+                            emit(Event::Declined { request, reason });
+                        }
+                        ServerMessage::MissionStarted(mission) => {
+                            emit(Event::SessionStarted {
+                                session_id: mission.id,
+                                sender: mission.sender_device,
+                                files: mission.info_map.into_keys().collect(),
+                            });
+                        }
+                        ServerMessage::TextReceived(sender, text) => {
+                            emit(Event::TextReceived { sender, text });
+                        }
+                        ServerMessage::Progress(file_id, mut rx) => {
+                            tokio::spawn(async move {
+                                while rx.changed().await.is_ok() {
+                                    emit(Event::Progress {
+                                        file_id: file_id.clone(),
+                                        bytes: *rx.borrow(),
+                                    });
+                                    // 限制输出频率
+                                    time::sleep(Duration::from_millis(200)).await;
+                                }
+                                let bytes = *rx.borrow();
+                                emit(Event::Finished { file_id, bytes });
+                            });
+                        }
+                        ServerMessage::CancelMission(mission) => {
+                            emit(Event::Cancelled {
+                                session_id: mission.as_ref().map(|m| m.id.clone()),
+                                sender: mission.map(|m| m.sender_device),
+                            });
                         }
-                        println!("file_id: {file_id}, finished");
+                        ServerMessage::UploadFinished(_, file_id, Err(e)) => {
+                            emit(Event::Error {
+                                message: format!("failed to receive {file_id}: {e}"),
+                            });
+                        }
+                        ServerMessage::UploadFinished(..) => {}
+                        ServerMessage::MissionFinished(summary) => {
+                            emit(Event::SessionFinished { summary });
+                        }
+                        ServerMessage::ShareAccess(..) => {}
                     }
-                    ServerMessage::CancelMission(mission_id) => {
-                        log::info!("Mission cancelled: {mission_id:?}");
+                }
+                line = stdin.next_line(), if stdin_open => {
+                    let line = match line {
+                        Ok(Some(line)) => line,
+                        // 标准输入关闭后只输出事件
+                        _ => {
+                            stdin_open = false;
+                            continue;
+                        }
+                    };
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str::<event::Command>(&line) {
+                        Ok(command) => {
+                            handle_command(command, &mut setting, &mut pending, &out_tx).await
+                        }
+                        Err(e) => emit(Event::Error {
+                            message: format!("invalid command: {e}"),
+                        }),
                     }
                 }
             }
         }
     });
-    server.start().await.unwrap();
+    if let Err(e) = server.start().await {
+        emit(Event::Error {
+            message: e.to_string(),
+        });
+        return Err(e);
+    }
+    Ok(())
+}
+
+// 发送方断开或请求已经结束时没有人等待回复，之后的 `accept`/`decline` 报告为未知请求
+fn drop_closed(pending: &mut HashMap<String, (HashSet<String>, oneshot::Sender<HashSet<String>>)>) {
+    pending.retain(|_, (_, agreed_tx)| !agreed_tx.is_closed());
+}
+
+async fn handle_command(
+    command: event::Command,
+    setting: &mut ServerSetting,
+    pending: &mut HashMap<String, (HashSet<String>, oneshot::Sender<HashSet<String>>)>,
+    out_tx: &mpsc::Sender<OutMessage>,
+) {
+    drop_closed(pending);
+    let message = match command {
+        event::Command::Accept { request_id, files } => {
+            let Some((file_ids, agreed_tx)) = pending.remove(&request_id) else {
+                emit(Event::Error {
+                    message: format!("unknown request `{request_id}`"),
+                });
+                return;
+            };
+            let agreed = match files {
+                Some(files) => file_ids.intersection(&files).cloned().collect(),
+                None => file_ids,
+            };
+            let _ = agreed_tx.send(agreed);
+            return;
+        }
+        event::Command::Decline { request_id } => {
+            match pending.remove(&request_id) {
+                Some((_, agreed_tx)) => {
+                    let _ = agreed_tx.send(HashSet::new());
+                }
+                None => emit(Event::Error {
+                    message: format!("unknown request `{request_id}`"),
+                }),
+            }
+            return;
+        }
+        event::Command::Cancel { session_id } => OutMessage::CancelMission(session_id),
+        event::Command::Block { fingerprint } => {
+            if !setting.blocked_devices.contains(&fingerprint) {
+                setting.blocked_devices.push(fingerprint);
+            }
+            OutMessage::UpdateSettings(setting.clone())
+        }
+        event::Command::Refresh => OutMessage::Refresh,
+        event::Command::Shutdown => OutMessage::Shutdown(Some(Duration::from_secs(5))),
+    };
+    let _ = out_tx.send(message).await;
 }
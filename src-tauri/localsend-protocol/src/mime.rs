@@ -0,0 +1,48 @@
+use std::{fs::File, io::Read, path::Path};
+
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+// 先按扩展名判断，没有扩展名或无法识别时读取文件头
+pub fn detect_mime(path: &Path) -> String {
+    if let Some(mime) = mime_guess::from_path(path).first_raw() {
+        return mime.to_string();
+    }
+    let mut head = Vec::with_capacity(8192);
+    if let Ok(file) = File::open(path) {
+        let _ = file.take(8192).read_to_end(&mut head);
+    }
+    sniff_mime(&head).to_string()
+}
+
+pub fn sniff_mime(head: &[u8]) -> &'static str {
+    if let Some(kind) = infer::get(head) {
+        return kind.mime_type();
+    }
+    // 没有 NUL 的 UTF-8 当作文本，末尾可能截断了一个多字节字符
+    let valid = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    if !head.is_empty() && valid && !head.contains(&0) {
+        return "text/plain";
+    }
+    OCTET_STREAM
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_mime() {
+        assert_eq!(detect_mime(Path::new("a.PNG")), "image/png");
+        assert_eq!(
+            detect_mime(Path::new("app.apk")),
+            "application/vnd.android.package-archive"
+        );
+        assert_eq!(sniff_mime(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), "image/png");
+        assert_eq!(sniff_mime("纯文本\n".as_bytes()), "text/plain");
+        assert_eq!(sniff_mime(&[0, 1, 2, 3]), OCTET_STREAM);
+        assert_eq!(sniff_mime(b""), OCTET_STREAM);
+    }
+}
@@ -1,8 +1,45 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
 
 use serde::{Deserialize, Serialize};
 
-use crate::model::{DeviceMessage, FileInfo};
+use crate::{
+    history::now_millis,
+    model::{DeviceMessage, FileInfo},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum FileState {
+    #[default]
+    Pending,
+    InProgress,
+    Finished,
+    Failed, // 会话过期之前发送方仍然可以重试
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct FileStatus {
+    pub state: FileState,
+    pub updated_at: i64, // Unix 时间戳（毫秒）
+    pub path: Option<PathBuf>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum MissionState {
+    #[default]
+    Active,
+    Completed, // 所有文件都已收到
+    Cancelled,
+    Expired, // 空闲超过 `session_timeout`
+}
 
 // 下载任务
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -11,21 +48,220 @@ pub struct Mission {
     pub sender_device: DeviceMessage,
     pub id_token_map: HashMap<String, String>,
     pub info_map: HashMap<String, FileInfo>,
+    #[serde(default)]
+    pub state: MissionState,
+    #[serde(default)]
+    pub files: HashMap<String, FileStatus>,
+    #[serde(default = "now_millis")]
+    pub created_at: i64,
+    #[serde(default = "now_millis")]
+    pub updated_at: i64,
+    #[serde(default)]
+    pub sender_ip: Option<IpAddr>, // v1 没有会话 id，取消时按发送方 IP 查找任务
+}
+
+// 任务结束时发出，文件按文件名排序
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MissionSummary {
+    pub id: String,
+    pub sender_device: DeviceMessage,
+    pub state: MissionState,
+    pub created_at: i64,
+    pub finished_at: i64,
+    pub files: Vec<(FileInfo, FileStatus)>,
 }
 
 impl Mission {
     pub fn new(info_map: HashMap<String, FileInfo>, sender_device: DeviceMessage) -> Self {
         let id = uuid::Uuid::new_v4().to_string();
+        let now = now_millis();
         let mut id_token_map = HashMap::new();
+        let mut files = HashMap::new();
         info_map.iter().for_each(|(id, _value)| {
             let token = uuid::Uuid::new_v4().to_string();
             id_token_map.insert(id.clone(), token.clone());
+            files.insert(
+                id.clone(),
+                FileStatus {
+                    updated_at: now,
+                    ..Default::default()
+                },
+            );
         });
         Self {
             id,
             sender_device,
             id_token_map,
             info_map,
+            state: MissionState::Active,
+            files,
+            created_at: now,
+            updated_at: now,
+            sender_ip: None,
+        }
+    }
+
+    pub fn start_file(&mut self, file_id: &str) {
+        self.update_file(file_id, FileState::InProgress, None, None);
+    }
+
+    // 返回 true 表示所有文件都已收到，任务完成
+    pub fn finish_file(&mut self, file_id: &str, result: Result<PathBuf, String>) -> bool {
+        match result {
+            Ok(path) => self.update_file(file_id, FileState::Finished, Some(path), None),
+            Err(e) => self.update_file(file_id, FileState::Failed, None, Some(e)),
+        }
+        if self.is_complete() {
+            self.state = MissionState::Completed;
         }
+        self.state == MissionState::Completed
+    }
+
+    fn update_file(
+        &mut self,
+        file_id: &str,
+        state: FileState,
+        path: Option<PathBuf>,
+        error: Option<String>,
+    ) {
+        let now = now_millis();
+        let status = self.files.entry(file_id.to_string()).or_default();
+        *status = FileStatus {
+            state,
+            updated_at: now,
+            path,
+            error,
+        };
+        self.updated_at = now;
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.info_map
+            .keys()
+            .all(|id| self.file_state(id) == FileState::Finished)
+    }
+
+    pub fn file_state(&self, file_id: &str) -> FileState {
+        self.files
+            .get(file_id)
+            .map(|status| status.state)
+            .unwrap_or_default()
+    }
+
+    // 没有正在上传的文件，且空闲超过 `timeout` 毫秒
+    pub fn is_expired(&self, now: i64, timeout: i64) -> bool {
+        now.saturating_sub(self.updated_at) > timeout
+            && self
+                .files
+                .values()
+                .all(|status| status.state != FileState::InProgress)
+    }
+
+    // 重启后不会有正在进行的上传
+    fn reset_in_progress(&mut self) {
+        for status in self.files.values_mut() {
+            if status.state == FileState::InProgress {
+                status.state = FileState::Failed;
+                status.error = Some("interrupted".to_string());
+            }
+        }
+    }
+
+    pub fn summary(&self, state: MissionState) -> MissionSummary {
+        let mut files = self
+            .info_map
+            .values()
+            .map(|info| {
+                let status = self.files.get(&info.id).cloned().unwrap_or_default();
+                (info.clone(), status)
+            })
+            .collect::<Vec<_>>();
+        files.sort_by(|(a, _), (b, _)| a.file_name.cmp(&b.file_name));
+        MissionSummary {
+            id: self.id.clone(),
+            sender_device: self.sender_device.clone(),
+            state,
+            created_at: self.created_at,
+            finished_at: now_millis(),
+            files,
+        }
+    }
+}
+
+// 未完成的任务保存到文件中，重启后发送方仍然可以用原来的 token 续传
+pub fn load_missions(path: &Path) -> io::Result<HashMap<String, Mission>> {
+    let content = fs::read_to_string(path)?;
+    let mut missions: HashMap<String, Mission> = serde_json::from_str(&content)?;
+    missions.values_mut().for_each(Mission::reset_in_progress);
+    Ok(missions)
+}
+
+pub fn save_missions(path: &Path, missions: &HashMap<String, Mission>) -> io::Result<()> {
+    write_missions(path, &serde_json::to_string(missions)?)
+}
+
+// 写入已经序列化好的任务，调用方可以先释放任务锁
+pub fn write_missions(path: &Path, content: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // 先写临时文件再重命名，避免写一半时退出导致文件损坏
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, content)?;
+    fs::rename(tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_missions() {
+        let path = std::env::temp_dir()
+            .join(format!("missions-{}", uuid::Uuid::new_v4()))
+            .join("sessions.json");
+        let file = FileInfo {
+            id: "a".to_string(),
+            file_name: "a.txt".to_string(),
+            ..Default::default()
+        };
+        let mission = Mission::new(
+            HashMap::from([(file.id.clone(), file)]),
+            DeviceMessage::default(),
+        );
+        let missions = HashMap::from([(mission.id.clone(), mission.clone())]);
+        save_missions(&path, &missions).unwrap();
+        let loaded = load_missions(&path).unwrap();
+        assert_eq!(loaded[&mission.id].id_token_map, mission.id_token_map);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_mission_state() {
+        let files = ["a", "b"].map(|id| {
+            let file = FileInfo {
+                id: id.to_string(),
+                file_name: format!("{id}.txt"),
+                ..Default::default()
+            };
+            (file.id.clone(), file)
+        });
+        let mut mission = Mission::new(HashMap::from(files), DeviceMessage::default());
+        assert_eq!(mission.file_state("a"), FileState::Pending);
+
+        mission.start_file("a");
+        assert!(!mission.is_expired(mission.updated_at + 10_000, 1000));
+        assert!(!mission.finish_file("a", Ok(PathBuf::from("a.txt"))));
+        // 失败的文件可以重试，不算完成
+        assert!(!mission.finish_file("b", Err("broken pipe".to_string())));
+        assert!(mission.is_expired(mission.updated_at + 10_000, 1000));
+        assert!(mission.finish_file("b", Ok(PathBuf::from("b.txt"))));
+        assert_eq!(mission.state, MissionState::Completed);
+
+        let summary = mission.summary(mission.state);
+        assert_eq!(summary.files[1].0.id, "b");
+        assert_eq!(summary.files[1].1.state, FileState::Finished);
+        assert_eq!(summary.files[1].1.error, None);
     }
 }
@@ -1,9 +1,18 @@
 use core::str;
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fs::{self, FileTimes},
+    io,
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
 
+use chrono::{DateTime, SecondsFormat, Utc};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::mime::detect_mime;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum DeviceType {
     Mobile,
@@ -13,7 +22,7 @@ pub enum DeviceType {
     Server,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Protocol {
     Http,
@@ -24,6 +33,7 @@ pub enum Protocol {
 #[serde(rename_all = "camelCase")]
 pub struct DeviceMessage {
     pub alias: String,
+    #[serde(default)] // v1 的部分消息没有
     pub version: String, // protocol version (major.minor)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub device_model: Option<String>,
@@ -34,9 +44,20 @@ pub struct DeviceMessage {
     pub port: Option<u16>,
     #[serde(skip_serializing_if = "Option::is_none")] // HTTP Legancy Mode Resp: None
     pub protocol: Option<Protocol>,
+    #[serde(default)]
     pub download: bool, // if download API (section 5.2, 5.3) is active (optional, default: false)
     #[serde(skip_serializing_if = "Option::is_none")] // Multicast: Some(true/false)
     pub announce: Option<bool>,
+    // 扩展：支持续传（`/upload` 的 `offset` 参数和 `/upload-offset`），官方客户端没有这个字段
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resumable: Option<bool>,
+}
+
+impl DeviceMessage {
+    // 只支持 v1 协议的旧版本
+    pub fn is_v1(&self) -> bool {
+        self.version.starts_with("1.")
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -46,6 +67,18 @@ pub struct FileRequest {
     pub files: HashMap<String, FileInfo>,
 }
 
+impl FileRequest {
+    // 只有一个 `text/plain` 文件且内容放在 `preview` 中时是文本消息
+    pub fn text(&self) -> Option<&str> {
+        match self.files.values().collect::<Vec<_>>().as_slice() {
+            [file] if file.file_type == TEXT_PLAIN => file.preview.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+pub const TEXT_PLAIN: &str = "text/plain";
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct FileInfo {
@@ -56,20 +89,233 @@ pub struct FileInfo {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub sha256: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub preview: Option<Vec<u8>>,
+    pub preview: Option<String>, // 文本消息的内容，或 `data:image/jpeg;base64,...` 缩略图
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<FileMetadata>, // v2.1 新增
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// 文件的修改和访问时间，ISO 8601 格式
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct FileMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accessed: Option<String>,
+}
+
+impl FileMetadata {
+    pub fn from_fs(metadata: &fs::Metadata) -> Option<Self> {
+        let format = |time: io::Result<SystemTime>| {
+            time.ok().map(|time| {
+                DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Millis, true)
+            })
+        };
+        let metadata = Self {
+            modified: format(metadata.modified()),
+            accessed: format(metadata.accessed()),
+        };
+        (metadata != Self::default()).then_some(metadata)
+    }
+
+    // 无法解析的时间忽略
+    pub fn file_times(&self) -> FileTimes {
+        let parse = |time: &Option<String>| {
+            time.as_deref()
+                .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+                .map(SystemTime::from)
+        };
+        let mut times = FileTimes::new();
+        if let Some(modified) = parse(&self.modified) {
+            times = times.set_modified(modified);
+        }
+        if let Some(accessed) = parse(&self.accessed) {
+            times = times.set_accessed(accessed);
+        }
+        times
+    }
+}
+
+impl FileInfo {
+    // 根据本地文件生成文件信息，id 随机生成
+    pub fn from_path(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{path:?} is not a file"),
+            ));
+        }
+        Ok(Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            file_name: path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+            size: metadata.len(),
+            file_type: detect_mime(path),
+            sha256: None,
+            preview: None,
+            metadata: FileMetadata::from_fs(&metadata),
+        })
+    }
+
+    pub fn text(text: &str) -> Self {
+        let id = uuid::Uuid::new_v4().to_string();
+        Self {
+            file_name: format!("{id}.txt"),
+            id,
+            size: text.len() as u64,
+            file_type: TEXT_PLAIN.to_string(),
+            sha256: None,
+            preview: Some(text.to_string()),
+            metadata: None,
+        }
+    }
+
+    // `file_name` 可以是 `/` 分隔的相对路径，拒绝 `..` 等可能逃出保存目录的路径
+    pub fn relative_path(&self) -> Option<PathBuf> {
+        let mut path = PathBuf::new();
+        for part in self.file_name.split(['/', '\\']) {
+            match part {
+                "" | "." => continue,
+                ".." => return None,
+                part => {
+                    let mut components = Path::new(part).components();
+                    match (components.next(), components.next()) {
+                        (Some(Component::Normal(name)), None) => path.push(name),
+                        _ => return None,
+                    }
+                }
+            }
+        }
+        (!path.as_os_str().is_empty()).then_some(path)
+    }
+}
+
+// 文件直接返回；目录递归列出所有文件，`file_name` 为以目录名开头的相对路径
+pub fn collect_files(path: &Path) -> io::Result<Vec<(PathBuf, FileInfo)>> {
+    if !fs::metadata(path)?.is_dir() {
+        return Ok(vec![(path.to_path_buf(), FileInfo::from_path(path)?)]);
+    }
+    let root = path.parent().unwrap_or(Path::new(""));
+    let mut files = vec![];
+    let mut dirs = vec![path.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let entry_path = entry.path();
+            // 不进入符号链接指向的目录，避免循环
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry_path);
+                continue;
+            }
+            if !fs::metadata(&entry_path).is_ok_and(|m| m.is_file()) {
+                continue;
+            }
+            let mut file_info = FileInfo::from_path(&entry_path)?;
+            file_info.file_name = entry_path
+                .strip_prefix(root)
+                .unwrap_or(&entry_path)
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            files.push((entry_path, file_info));
+        }
+    }
+    files.sort_by(|a, b| a.1.file_name.cmp(&b.1.file_name));
+    Ok(files)
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct FileResponse {
     pub session_id: String,
     pub files: HashMap<String, String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UploadParam {
     pub session_id: String,
     pub file_id: String,
     pub token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>, // 扩展：从这个字节开始续传
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PrepareDownloadParam {
+    pub session_id: Option<String>, // 已经同意过的会话不需要再次同意
+    pub pin: Option<String>,
+    pub token: Option<String>, // 扩展：分享链接中的 token，可以代替 PIN
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadParam {
+    pub session_id: String,
+    pub file_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadResponse {
+    pub info: DeviceMessage,
+    pub session_id: String,
+    pub files: HashMap<String, FileInfo>,
+}
+
+// `/upload-offset` 的返回值，接收方已经收到的字节数
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadOffset {
+    pub offset: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relative_path() {
+        let file = |name: &str| FileInfo {
+            file_name: name.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            file("photos/2024/a.jpg").relative_path(),
+            Some(PathBuf::from("photos").join("2024").join("a.jpg"))
+        );
+        assert_eq!(
+            file("/etc/./passwd").relative_path(),
+            Some(PathBuf::from("etc").join("passwd"))
+        );
+        assert_eq!(file("a/../../b").relative_path(), None);
+        assert_eq!(file("..\\b").relative_path(), None);
+        assert_eq!(file("").relative_path(), None);
+    }
+
+    #[test]
+    fn test_collect_files() {
+        let dir = std::env::temp_dir().join(format!("collect-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        fs::write(dir.join("sub").join("b.txt"), "bb").unwrap();
+        let name = dir.file_name().unwrap().to_string_lossy().to_string();
+        let files = collect_files(&dir).unwrap();
+        let names = files
+            .iter()
+            .map(|(_, f)| f.file_name.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![format!("{name}/a.txt"), format!("{name}/sub/b.txt")]
+        );
+        let metadata = files[0].1.metadata.clone().unwrap();
+        assert!(metadata.modified.unwrap().ends_with('Z'));
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
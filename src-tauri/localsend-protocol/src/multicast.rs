@@ -3,52 +3,70 @@ use std::{
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
 };
 
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::net::UdpSocket;
 
 use crate::model::DeviceMessage;
 
+// `interfaces` 为发送组播消息的网卡地址，某个网卡出错时继续发送其他网卡，全部失败才返回错误
 pub async fn multicast_message(
     recv_addr: &SocketAddrV4,
     message: &DeviceMessage,
+    interfaces: &[Ipv4Addr],
 ) -> io::Result<()> {
-    let ipv4s = if_addrs::get_if_addrs()
-        .unwrap_or_default()
-        .into_iter()
-        .filter_map(|iface| match iface.addr {
-            if_addrs::IfAddr::V4(v4) if !v4.is_loopback() => Some(v4.ip.into()),
-            _ => None,
-        })
-        .collect::<Vec<Ipv4Addr>>();
-    // dbg!(&ipv4s);
-    for ipv4 in ipv4s {
-        let local_addr = SocketAddrV4::new(ipv4, 0);
-        let socket = UdpSocket::bind(&local_addr).await?;
-        let message = serde_json::json!(message).to_string();
-        // log::info!("Send multicast message on {:?}", socket);
-        // 多发几次
-        for _ in 0..5 {
-            socket.send_to(message.as_bytes(), &recv_addr).await?;
+    let message = serde_json::json!(message).to_string();
+    let mut last_err = None;
+    let mut sent = false;
+    for ipv4 in interfaces {
+        match send_on_interface(recv_addr, &message, *ipv4).await {
+            Ok(()) => sent = true,
+            Err(e) => {
+                log::warn!("send multicast message on {ipv4} error: {e}");
+                last_err = Some(e);
+            }
         }
     }
+    match (sent, last_err) {
+        (false, Some(e)) => Err(e),
+        _ => Ok(()),
+    }
+}
+
+async fn send_on_interface(
+    recv_addr: &SocketAddrV4,
+    message: &str,
+    ipv4: Ipv4Addr,
+) -> io::Result<()> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(ipv4, 0)).await?;
+    // 多发几次
+    for _ in 0..5 {
+        socket.send_to(message.as_bytes(), recv_addr).await?;
+    }
     Ok(())
 }
 
-pub async fn multicast_listener(addr: &SocketAddrV4) -> io::Result<(DeviceMessage, SocketAddr)> {
+// 绑定组播端口并只在 `interfaces` 上加入组播组
+// 开启 SO_REUSEADDR/SO_REUSEPORT，多个实例可以同时监听
+pub fn bind_multicast(addr: &SocketAddrV4, interfaces: &[Ipv4Addr]) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
     let local_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, addr.port());
-    let socket = UdpSocket::bind(&local_addr).await?;
-    let ipv4s = if_addrs::get_if_addrs()
-        .unwrap_or_default()
-        .into_iter()
-        .filter_map(|iface| match iface.addr {
-            if_addrs::IfAddr::V4(v4) if !v4.is_loopback() => Some(v4.ip.into()),
-            _ => None,
-        })
-        .collect::<Vec<Ipv4Addr>>();
-    for iface in ipv4s.iter() {
-        socket.join_multicast_v4(addr.ip().to_owned(), *iface)?;
+    socket.bind(&local_addr.into())?;
+    let socket = UdpSocket::from_std(socket.into())?;
+    for iface in interfaces.iter() {
+        if let Err(e) = socket.join_multicast_v4(addr.ip().to_owned(), *iface) {
+            log::warn!("join multicast on {iface} error: {e}");
+        }
     }
-    let mut buf = vec![0u8; 1024];
     log::info!("start multicast listening on {:?}", socket);
+    Ok(socket)
+}
+
+pub async fn multicast_listener(socket: &UdpSocket) -> io::Result<(DeviceMessage, SocketAddr)> {
+    let mut buf = vec![0u8; 1024];
     let (len, sender_addr) = socket.recv_from(&mut buf).await?;
     let message = serde_json::from_slice::<DeviceMessage>(&buf[..len]).unwrap_or_default();
     log::info!("accept message from multicast: {:?}", message);
@@ -57,7 +75,10 @@ pub async fn multicast_listener(addr: &SocketAddrV4) -> io::Result<(DeviceMessag
 
 #[cfg(test)]
 mod tests {
-    use crate::model::*;
+    use crate::{
+        interface::list_interfaces,
+        model::{DeviceMessage, DeviceType, Protocol},
+    };
 
     use super::*;
 
@@ -74,8 +95,27 @@ mod tests {
             protocol: Some(Protocol::Http),
             download: true,
             announce: Some(true),
+            resumable: None,
         };
-        multicast_message(&recv, &message).await.unwrap();
+        let interfaces = list_interfaces()
+            .into_iter()
+            .map(|iface| iface.ip)
+            .collect::<Vec<_>>();
+        multicast_message(&recv, &message, &interfaces)
+            .await
+            .unwrap();
+
+        // 不存在的网卡地址不影响其他网卡，全部失败时才返回错误
+        let missing = Ipv4Addr::new(192, 0, 2, 1);
+        assert!(multicast_message(&recv, &message, &[missing])
+            .await
+            .is_err());
+        if !interfaces.is_empty() {
+            let interfaces = [&[missing], interfaces.as_slice()].concat();
+            multicast_message(&recv, &message, &interfaces)
+                .await
+                .unwrap();
+        }
     }
 
     #[tokio::test]
@@ -85,8 +125,13 @@ mod tests {
         //     .is_test(true)
         //     .try_init();
         let addr: SocketAddrV4 = "224.0.0.167:53317".parse().unwrap();
+        let interfaces = list_interfaces()
+            .into_iter()
+            .map(|iface| iface.ip)
+            .collect::<Vec<_>>();
+        let socket = bind_multicast(&addr, &interfaces).unwrap();
         for _i in 0..5 {
-            let (message, sender_addr) = multicast_listener(&addr).await.unwrap();
+            let (message, sender_addr) = multicast_listener(&socket).await.unwrap();
             dbg!(message, sender_addr);
         }
     }
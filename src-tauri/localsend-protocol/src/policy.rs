@@ -0,0 +1,254 @@
+use std::{fmt, path::Path, str::FromStr};
+
+use chrono::{Local, NaiveTime};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    interface::wildcard_match,
+    model::{FileInfo, FileRequest},
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PolicyAction {
+    Accept,
+    Decline,
+    #[default]
+    Ask, // 交给界面询问
+}
+
+// 一天中的时间段 `HH:MM-HH:MM`，结束早于开始时跨越午夜
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeRange {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TimeRange {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl FromStr for TimeRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |t: &str| {
+            NaiveTime::parse_from_str(t.trim(), "%H:%M")
+                .map_err(|e| format!("invalid time range `{s}`: {e}"))
+        };
+        let (start, end) = s
+            .split_once('-')
+            .ok_or(format!("invalid time range `{s}`, expected `HH:MM-HH:MM`"))?;
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+impl TryFrom<String> for TimeRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<TimeRange> for String {
+    fn from(range: TimeRange) -> Self {
+        range.to_string()
+    }
+}
+
+impl fmt::Display for TimeRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+// 所有条件都满足时规则生效，空条件不限制
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct PolicyRule {
+    pub fingerprints: Vec<String>,
+    pub aliases: Vec<String>,    // 支持 `*` 通配
+    pub file_types: Vec<String>, // MIME（如 `image/*`）或扩展名（如 `png`），每个文件都要匹配
+    pub max_files: Option<usize>,
+    pub max_total_size: Option<u64>, // bytes
+    pub time_range: Option<TimeRange>,
+    pub action: PolicyAction,
+}
+
+impl PolicyRule {
+    pub fn matches(&self, file_req: &FileRequest, now: NaiveTime) -> bool {
+        let sender = &file_req.info;
+        if !self.fingerprints.is_empty() && !self.fingerprints.contains(&sender.fingerprint) {
+            return false;
+        }
+        if !self.aliases.is_empty()
+            && !self
+                .aliases
+                .iter()
+                .any(|pattern| wildcard_match(pattern, &sender.alias))
+        {
+            return false;
+        }
+        if !self.file_types.is_empty()
+            && !file_req
+                .files
+                .values()
+                .all(|file| self.file_types.iter().any(|t| file_type_match(t, file)))
+        {
+            return false;
+        }
+        if self.max_files.is_some_and(|max| file_req.files.len() > max) {
+            return false;
+        }
+        // 大小由发送方提供，相加时不能溢出
+        let total_size = file_req
+            .files
+            .values()
+            .fold(0u64, |total, file| total.saturating_add(file.size));
+        if self.max_total_size.is_some_and(|max| total_size > max) {
+            return false;
+        }
+        if self.time_range.is_some_and(|range| !range.contains(now)) {
+            return false;
+        }
+        true
+    }
+}
+
+pub(crate) fn file_type_match(pattern: &str, file: &FileInfo) -> bool {
+    let pattern = pattern.trim().to_lowercase();
+    if pattern.contains('/') {
+        return wildcard_match(&pattern, &file.file_type.to_lowercase());
+    }
+    let extension = Path::new(&file.file_name)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    wildcard_match(pattern.trim_start_matches('.'), &extension)
+}
+
+// 收到文件传入请求时按顺序匹配规则，第一条匹配的规则决定结果
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AcceptPolicy {
+    pub rules: Vec<PolicyRule>,
+    pub default_action: PolicyAction, // 没有规则匹配时
+}
+
+impl AcceptPolicy {
+    pub fn accept_all() -> Self {
+        Self {
+            rules: vec![],
+            default_action: PolicyAction::Accept,
+        }
+    }
+
+    pub fn evaluate(&self, file_req: &FileRequest) -> PolicyAction {
+        self.evaluate_at(file_req, Local::now().time())
+    }
+
+    pub fn evaluate_at(&self, file_req: &FileRequest, now: NaiveTime) -> PolicyAction {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(file_req, now))
+            .map(|rule| rule.action)
+            .unwrap_or(self.default_action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::model::DeviceMessage;
+
+    fn file_req(fingerprint: &str, files: &[(&str, &str, u64)]) -> FileRequest {
+        FileRequest {
+            info: DeviceMessage {
+                alias: "team-laptop".to_string(),
+                fingerprint: fingerprint.to_string(),
+                ..Default::default()
+            },
+            files: files
+                .iter()
+                .map(|(name, file_type, size)| {
+                    let file = FileInfo {
+                        id: name.to_string(),
+                        file_name: name.to_string(),
+                        size: *size,
+                        file_type: file_type.to_string(),
+                        ..Default::default()
+                    };
+                    (name.to_string(), file)
+                })
+                .collect::<HashMap<_, _>>(),
+        }
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let policy: AcceptPolicy = serde_json::from_str(
+            r#"{
+                "rules": [
+                    {"fingerprints": ["trusted"], "action": "accept"},
+                    {"aliases": ["team-*"], "fileTypes": ["image/*", "pdf"], "maxTotalSize": 100,
+                     "timeRange": "09:00-18:00", "action": "accept"}
+                ],
+                "defaultAction": "decline"
+            }"#,
+        )
+        .unwrap();
+        let noon = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+        let night = NaiveTime::from_hms_opt(23, 0, 0).unwrap();
+
+        let req = file_req("trusted", &[("a.zip", "application/zip", 1000)]);
+        assert_eq!(policy.evaluate_at(&req, night), PolicyAction::Accept);
+
+        let req = file_req("other", &[("a.png", "image/png", 10), ("b.PDF", "", 10)]);
+        assert_eq!(policy.evaluate_at(&req, noon), PolicyAction::Accept);
+        assert_eq!(policy.evaluate_at(&req, night), PolicyAction::Decline);
+
+        let req = file_req("other", &[("a.png", "image/png", 10), ("b.zip", "", 10)]);
+        assert_eq!(policy.evaluate_at(&req, noon), PolicyAction::Decline);
+
+        assert_eq!(
+            AcceptPolicy::default().evaluate_at(&req, noon),
+            PolicyAction::Ask
+        );
+
+        // 合计超过 u64::MAX 时不能回绕成很小的值
+        let half = u64::MAX / 2 + 1;
+        let req = file_req(
+            "other",
+            &[("a.png", "image/png", half), ("b.png", "image/png", half)],
+        );
+        assert_eq!(policy.evaluate_at(&req, noon), PolicyAction::Decline);
+    }
+
+    #[test]
+    fn test_time_range() {
+        let range: TimeRange = "22:00-06:00".parse().unwrap();
+        assert!(range.contains(NaiveTime::from_hms_opt(23, 30, 0).unwrap()));
+        assert!(range.contains(NaiveTime::from_hms_opt(5, 0, 0).unwrap()));
+        assert!(!range.contains(NaiveTime::from_hms_opt(12, 0, 0).unwrap()));
+        assert_eq!(range.to_string(), "22:00-06:00");
+        assert!("25:00-06:00".parse::<TimeRange>().is_err());
+    }
+}
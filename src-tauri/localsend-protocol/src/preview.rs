@@ -0,0 +1,129 @@
+use std::{fs, path::Path, process::Command, sync::Arc};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::model::FileInfo;
+
+pub const DEFAULT_MAX_PREVIEW_SIZE: usize = 32 * 1024; // 编码前的 JPEG 大小
+const MAX_SOURCE_SIZE: u64 = 64 * 1024 * 1024; // 太大的图片不生成预览
+#[cfg(feature = "thumbnail")]
+const THUMBNAIL_SIZES: [u32; 3] = [256, 160, 96];
+
+// 从文件生成 JPEG 预览的扩展点，例如截取视频第一帧
+pub type PreviewHook = Arc<dyn Fn(&Path) -> Option<Vec<u8>> + Send + Sync>;
+
+// 生成 `data:image/jpeg;base64,...` 形式的预览，超过 `max_size` 时放弃
+#[derive(Clone)]
+pub struct PreviewGenerator {
+    pub max_size: usize,
+    video_hook: Option<PreviewHook>,
+}
+
+impl Default for PreviewGenerator {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_MAX_PREVIEW_SIZE,
+            video_hook: None,
+        }
+    }
+}
+
+impl PreviewGenerator {
+    pub fn with_video_hook(mut self, hook: PreviewHook) -> Self {
+        self.video_hook = Some(hook);
+        self
+    }
+
+    pub fn generate(&self, path: &Path, mime: &str) -> Option<String> {
+        let jpeg = if mime.starts_with("image/") {
+            if fs::metadata(path).ok()?.len() > MAX_SOURCE_SIZE {
+                return None;
+            }
+            thumbnail(&fs::read(path).ok()?, self.max_size)?
+        } else if mime.starts_with("video/") {
+            let frame = (self.video_hook.as_ref()?)(path)?;
+            match frame.len() <= self.max_size {
+                true => frame,
+                false => thumbnail(&frame, self.max_size)?,
+            }
+        } else {
+            return None;
+        };
+        Some(format!("data:image/jpeg;base64,{}", STANDARD.encode(jpeg)))
+    }
+
+    // 已有预览（例如文本消息）时不覆盖
+    pub fn fill(&self, file_info: &mut FileInfo, path: &Path) {
+        if file_info.preview.is_none() {
+            file_info.preview = self.generate(path, &file_info.file_type);
+        }
+    }
+}
+
+// 逐步缩小尺寸直到不超过 `max_size`
+#[cfg(feature = "thumbnail")]
+fn thumbnail(bytes: &[u8], max_size: usize) -> Option<Vec<u8>> {
+    use std::io::Cursor;
+
+    let image = image::load_from_memory(bytes).ok()?;
+    for size in THUMBNAIL_SIZES {
+        let mut jpeg = Cursor::new(vec![]);
+        image
+            .thumbnail(size, size)
+            .to_rgb8()
+            .write_to(&mut jpeg, image::ImageFormat::Jpeg)
+            .ok()?;
+        if jpeg.get_ref().len() <= max_size {
+            return Some(jpeg.into_inner());
+        }
+    }
+    None
+}
+
+#[cfg(not(feature = "thumbnail"))]
+fn thumbnail(_bytes: &[u8], _max_size: usize) -> Option<Vec<u8>> {
+    None
+}
+
+// 用 ffmpeg 截取视频第一帧，没有安装 ffmpeg 时不生成预览
+pub fn ffmpeg_hook() -> PreviewHook {
+    Arc::new(|path| {
+        let output = Command::new("ffmpeg")
+            .args(["-v", "error", "-i"])
+            .arg(path)
+            .args(["-frames:v", "1", "-vf", "scale=256:-2"])
+            .args(["-f", "image2pipe", "-vcodec", "mjpeg", "-"])
+            .output()
+            .ok()?;
+        (output.status.success() && !output.stdout.is_empty()).then_some(output.stdout)
+    })
+}
+
+#[cfg(all(test, feature = "thumbnail"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thumbnail() {
+        let path = std::env::temp_dir().join(format!("preview-{}.png", uuid::Uuid::new_v4()));
+        image::RgbImage::from_fn(1024, 768, |x, y| image::Rgb([x as u8, y as u8, 128]))
+            .save(&path)
+            .unwrap();
+        let generator = PreviewGenerator::default();
+        let preview = generator.generate(&path, "image/png").unwrap();
+        let jpeg = STANDARD
+            .decode(preview.strip_prefix("data:image/jpeg;base64,").unwrap())
+            .unwrap();
+        assert!(jpeg.len() <= DEFAULT_MAX_PREVIEW_SIZE);
+        let thumbnail = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (256, 192));
+
+        let tiny = PreviewGenerator {
+            max_size: 16,
+            ..Default::default()
+        };
+        assert_eq!(tiny.generate(&path, "image/png"), None);
+        assert_eq!(generator.generate(&path, "video/mp4"), None);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,11 +1,19 @@
-use std::{net::SocketAddr, path::PathBuf, time::Duration};
+use std::{collections::HashMap, io::SeekFrom, net::SocketAddr, path::PathBuf, time::Duration};
 
-use reqwest::Client;
-use tokio::fs;
+use reqwest::{Body, Client, StatusCode};
+use serde::Serialize;
+use tokio::{
+    fs::File,
+    io::AsyncSeekExt,
+    sync::{mpsc, watch},
+};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tokio_util::io::ReaderStream;
 
 use crate::{
-    model::{FileRequest, FileResponse, UploadParam},
+    model::{DeviceMessage, FileInfo, FileRequest, FileResponse, UploadOffset, UploadParam},
     server::ServerSetting,
+    throttle::global_throttle,
 };
 
 pub async fn send_register(
@@ -15,41 +23,180 @@ pub async fn send_register(
     let url = format!("http://{}/api/localsend/v2/register", addr);
     Client::new()
         .post(url)
-        .body(serde_json::json!(setting.to_device_message(None)).to_string())
+        .json(&setting.to_device_message(None))
         .timeout(Duration::from_millis(100))
         .send()
         .await?;
     Ok(())
 }
 
+// 获取对方设备信息
+pub async fn info(addr: &SocketAddr) -> Result<DeviceMessage, reqwest::Error> {
+    let url = format!("http://{}/api/localsend/v2/info", addr);
+    Client::new()
+        .get(url)
+        .timeout(Duration::from_secs(2))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+}
+
+// 注册到对方设备，并返回对方的设备信息，对方只支持 v1 时使用 v1 的接口
+pub async fn register(
+    setting: &ServerSetting,
+    addr: &SocketAddr,
+) -> Result<DeviceMessage, reqwest::Error> {
+    let register = |version: &str| {
+        Client::new()
+            .post(format!("http://{addr}/api/localsend/{version}/register"))
+            .json(&setting.to_device_message(None))
+            .timeout(Duration::from_secs(2))
+            .send()
+    };
+    let mut response = register("v2").await?;
+    let v1 = response.status() == StatusCode::NOT_FOUND;
+    if v1 {
+        response = register("v1").await?;
+    }
+    let mut device: DeviceMessage = response.error_for_status()?.json().await?;
+    if v1 && !device.is_v1() {
+        device.version = "1.0".to_string();
+    }
+    Ok(device)
+}
+
 pub async fn prepare_upload(
     file_req: FileRequest,
     addr: &SocketAddr,
+    pin: Option<&str>,
 ) -> Result<FileResponse, Box<dyn std::error::Error>> {
     let url = format!("http://{}/api/localsend/v2/prepare-upload", addr);
-    let response = Client::new()
+    let mut request = Client::new()
         .post(url)
         .header("Content-Type", "application/json")
         .body(serde_json::json!(file_req).to_string())
-        .timeout(Duration::from_secs(60))
-        .send()
-        .await?;
+        .timeout(Duration::from_secs(60));
+    if let Some(pin) = pin {
+        request = request.query(&[("pin", pin)]);
+    }
+    let response = request.send().await?;
+    match response.status() {
+        StatusCode::UNAUTHORIZED => return Err("PIN required or wrong PIN".into()),
+        StatusCode::FORBIDDEN => return Err("rejected by receiver".into()),
+        StatusCode::TOO_MANY_REQUESTS => return Err("too many requests, try again later".into()),
+        StatusCode::PAYLOAD_TOO_LARGE => return Err("files exceed receiver's size limit".into()),
+        StatusCode::INSUFFICIENT_STORAGE => {
+            let reason = response.text().await.unwrap_or_default();
+            return Err(format!("receiver declined: {reason}").into());
+        }
+        status if !status.is_success() => return Err(format!("prepare upload: {status}").into()),
+        // 不需要上传文件，例如文本消息
+        StatusCode::NO_CONTENT => return Ok(FileResponse::default()),
+        _ => {}
+    }
     let text = response.text().await?;
     // dbg!(&text);
     Ok(serde_json::from_str(&text)?)
 }
 
+// `progress` 不为 None 时报告已发送的字节数
 pub async fn upload(
     upload_param: UploadParam,
     file_path: &PathBuf,
     addr: &SocketAddr,
+    progress: Option<watch::Sender<usize>>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let url = format!(
-        "http://{}/api/localsend/v2/upload?sessionId={}&fileId={}&token={}",
-        addr, upload_param.session_id, upload_param.file_id, upload_param.token
-    );
-    let file = fs::read(file_path).await?;
-    Client::new().post(url).body(file).send().await?;
+    let url = format!("http://{}/api/localsend/v2/upload", addr);
+    let offset = upload_param.offset.unwrap_or(0);
+    upload_file(url, &upload_param, file_path, offset, progress).await
+}
+
+// v1 和 v2 共用，`query` 为上传接口的参数
+pub(crate) async fn upload_file(
+    url: String,
+    query: &impl Serialize,
+    file_path: &PathBuf,
+    offset: u64,
+    progress: Option<watch::Sender<usize>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut file = File::open(file_path).await?;
+    // 续传时跳过对方已经收到的部分
+    if offset > 0 {
+        file.seek(SeekFrom::Start(offset)).await?;
+    }
+    // 限速需要异步等待，在单独的任务中读取文件
+    let (chunk_tx, chunk_rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        let mut stream = ReaderStream::new(file);
+        let mut sent = offset as usize;
+        while let Some(chunk) = stream.next().await {
+            if let Ok(chunk) = &chunk {
+                global_throttle().consume(chunk.len()).await;
+                sent += chunk.len();
+                if let Some(progress) = &progress {
+                    let _ = progress.send(sent);
+                }
+            }
+            if chunk_tx.send(chunk).await.is_err() {
+                break;
+            }
+        }
+    });
+    Client::new()
+        .post(url)
+        .query(query)
+        .body(Body::wrap_stream(ReceiverStream::new(chunk_rx)))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+// 查询对方已经收到的字节数，只有 `resumable` 的设备支持
+pub async fn upload_offset(
+    upload_param: &UploadParam,
+    addr: &SocketAddr,
+) -> Result<u64, reqwest::Error> {
+    let url = format!("http://{}/api/localsend/v2/upload-offset", addr);
+    let resp: UploadOffset = Client::new()
+        .get(url)
+        .query(upload_param)
+        .timeout(Duration::from_secs(5))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(resp.offset)
+}
+
+// 发送文本消息，对方要求上传时当作 .txt 文件发送
+pub async fn send_text(
+    setting: &ServerSetting,
+    text: &str,
+    addr: &SocketAddr,
+    pin: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file_info = FileInfo::text(text);
+    let file_req = FileRequest {
+        info: setting.to_device_message(None),
+        files: HashMap::from([(file_info.id.clone(), file_info)]),
+    };
+    let resp = prepare_upload(file_req, addr, pin).await?;
+    for (file_id, token) in resp.files {
+        let url = format!(
+            "http://{}/api/localsend/v2/upload?sessionId={}&fileId={}&token={}",
+            addr, resp.session_id, file_id, token
+        );
+        Client::new()
+            .post(url)
+            .body(text.to_string())
+            .send()
+            .await?
+            .error_for_status()?;
+    }
     Ok(())
 }
 
@@ -58,6 +205,11 @@ pub async fn cancel(session_id: String, addr: &SocketAddr) -> Result<(), reqwest
         "http://{}/api/localsend/v2/cancel?sessionId={}",
         addr, session_id
     );
-    Client::new().post(url).send().await?;
+    let response = Client::new().post(url).send().await?;
+    // v1 没有会话 id，取消对方正在接收的全部文件
+    if response.status() == StatusCode::NOT_FOUND {
+        let url = format!("http://{addr}/api/localsend/v1/cancel");
+        Client::new().post(url).send().await?;
+    }
     Ok(())
 }
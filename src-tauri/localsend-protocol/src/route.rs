@@ -0,0 +1,170 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    interface::wildcard_match,
+    model::{DeviceMessage, FileInfo},
+    policy::file_type_match,
+};
+
+const PLACEHOLDERS: [&str; 7] = [
+    "store",
+    "alias",
+    "fingerprint",
+    "yyyy",
+    "mm",
+    "dd",
+    "yyyy-mm-dd",
+];
+
+// 按发送方和文件类型选择保存目录，所有条件都满足时生效，空条件不限制
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RouteRule {
+    pub fingerprints: Vec<String>,
+    pub aliases: Vec<String>,    // 支持 `*` 通配
+    pub file_types: Vec<String>, // MIME（如 `image/*`）或扩展名（如 `log`）
+    // 目录模板，如 `{store}/{alias}/{yyyy-mm-dd}`，相对路径以保存目录为基准
+    pub destination: String,
+}
+
+impl RouteRule {
+    pub fn matches(&self, sender: &DeviceMessage, file: &FileInfo) -> bool {
+        (self.fingerprints.is_empty() || self.fingerprints.contains(&sender.fingerprint))
+            && (self.aliases.is_empty()
+                || self
+                    .aliases
+                    .iter()
+                    .any(|pattern| wildcard_match(pattern, &sender.alias)))
+            && (self.file_types.is_empty()
+                || self.file_types.iter().any(|t| file_type_match(t, file)))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.destination.trim().is_empty() {
+            return Err("route destination must not be empty".to_string());
+        }
+        let mut rest = self.destination.as_str();
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or(format!("unclosed `{{` in `{}`", self.destination))?;
+            let name = &rest[start + 1..start + end];
+            if !PLACEHOLDERS.contains(&name) {
+                return Err(format!(
+                    "unknown placeholder `{{{name}}}` in `{}`",
+                    self.destination
+                ));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        Ok(())
+    }
+
+    fn expand(&self, store: &Path, sender: &DeviceMessage, time: DateTime<Local>) -> PathBuf {
+        let destination = self
+            .destination
+            .replace("{store}", &store.to_string_lossy())
+            .replace("{alias}", &path_component(&sender.alias))
+            .replace("{fingerprint}", &path_component(&sender.fingerprint))
+            .replace("{yyyy-mm-dd}", &time.format("%Y-%m-%d").to_string())
+            .replace("{yyyy}", &time.format("%Y").to_string())
+            .replace("{mm}", &time.format("%m").to_string())
+            .replace("{dd}", &time.format("%d").to_string());
+        match self.destination.contains("{store}") {
+            true => PathBuf::from(destination),
+            // 绝对路径时 `join` 返回它本身
+            false => store.join(destination),
+        }
+    }
+}
+
+// 设备名由发送方决定，不能带有路径分隔符或 `..`
+fn path_component(name: &str) -> String {
+    let name = name
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    match name.trim_matches('.').is_empty() {
+        true => "_".to_string(),
+        false => name,
+    }
+}
+
+// 按顺序匹配，第一条匹配的规则决定保存目录，都不匹配时保存到 `store`
+pub fn resolve_destination(
+    routes: &[RouteRule],
+    store: &Path,
+    sender: &DeviceMessage,
+    file: &FileInfo,
+    time: DateTime<Local>,
+) -> PathBuf {
+    routes
+        .iter()
+        .find(|rule| rule.matches(sender, file))
+        .map(|rule| rule.expand(store, sender, time))
+        .unwrap_or(store.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_resolve_destination() {
+        let routes: Vec<RouteRule> = serde_json::from_str(
+            r#"[
+                {"fileTypes": ["image/*"], "destination": "/home/me/Pictures/{yyyy}/{mm}"},
+                {"aliases": ["build-*"], "fileTypes": ["log"], "destination": "logs/{alias}"},
+                {"destination": "{store}/{alias}/{yyyy-mm-dd}"}
+            ]"#,
+        )
+        .unwrap();
+        assert!(routes.iter().all(|rule| rule.validate().is_ok()));
+        let time = Local.with_ymd_and_hms(2024, 3, 9, 12, 0, 0).unwrap();
+        let store = Path::new("data");
+        let sender = |alias: &str| DeviceMessage {
+            alias: alias.to_string(),
+            ..Default::default()
+        };
+        let file = |name: &str, file_type: &str| FileInfo {
+            file_name: name.to_string(),
+            file_type: file_type.to_string(),
+            ..Default::default()
+        };
+
+        let resolve =
+            |alias, file| resolve_destination(&routes, store, &sender(alias), &file, time);
+        assert_eq!(
+            resolve("phone", file("a.jpg", "image/jpeg")),
+            Path::new("/home/me/Pictures/2024/03")
+        );
+        assert_eq!(
+            resolve("build-01", file("out.LOG", "text/plain")),
+            Path::new("data/logs/build-01")
+        );
+        assert_eq!(
+            resolve("..", file("a.txt", "text/plain")),
+            Path::new("data/_/2024-03-09")
+        );
+        assert_eq!(
+            resolve_destination(&[], store, &sender("phone"), &file("a", ""), time),
+            store
+        );
+
+        let rule = RouteRule {
+            destination: "{store}/{date}".to_string(),
+            ..Default::default()
+        };
+        assert!(rule.validate().is_err());
+    }
+}
@@ -0,0 +1,401 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc, watch, Semaphore},
+    task::JoinSet,
+};
+
+use crate::{
+    model::{DeviceMessage, FileInfo, FileRequest, UploadParam},
+    request::{prepare_upload, register, upload, upload_offset},
+    server::ServerSetting,
+    v1,
+};
+
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+// 发送过程中的事件，每个事件都带上目标设备的地址
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum SendEvent {
+    #[serde(rename_all = "camelCase")]
+    Accepted {
+        target: SocketAddr,
+        session_id: String,
+        file_ids: Vec<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Progress {
+        target: SocketAddr,
+        file_id: String,
+        bytes: usize,
+    },
+    #[serde(rename_all = "camelCase")]
+    Retrying {
+        target: SocketAddr,
+        file_id: String,
+        attempt: u32,
+        error: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    FileFinished {
+        target: SocketAddr,
+        file_id: String,
+        error: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Finished { result: SendResult },
+}
+
+// 一个设备的发送结果，保存下来可以在重启后续传失败的文件
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SendResult {
+    pub target: SocketAddr,
+    pub session_id: Option<String>,
+    pub accepted: Vec<String>,           // 对方同意的文件 id
+    pub finished: Vec<String>,           // 上传成功的文件 id
+    pub failed: HashMap<String, String>, // 上传失败的文件 id 和错误
+    pub error: Option<String>,           // prepare-upload 失败或被拒绝
+    pub declined: bool,
+    #[serde(default)]
+    pub peer: Option<DeviceMessage>, // register 返回的对方设备信息
+    #[serde(default)]
+    pub resumable: bool, // 对方支持从断开的位置续传
+    #[serde(default)]
+    pub tokens: HashMap<String, String>, // 重试时需要的上传 token
+    #[serde(default)]
+    pub v1: bool, // 对方只支持 v1 协议
+}
+
+impl SendResult {
+    fn new(target: SocketAddr) -> Self {
+        Self {
+            target,
+            session_id: None,
+            accepted: vec![],
+            finished: vec![],
+            failed: HashMap::new(),
+            error: None,
+            declined: false,
+            peer: None,
+            resumable: false,
+            tokens: HashMap::new(),
+            v1: false,
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.error.is_none() && self.failed.is_empty()
+    }
+}
+
+// 向一个设备发送文件：prepare-upload 后上传被同意的文件
+pub async fn send_files(
+    setting: &ServerSetting,
+    target: SocketAddr,
+    files: &[(PathBuf, FileInfo)],
+    pin: Option<&str>,
+    events: mpsc::Sender<SendEvent>,
+) -> SendResult {
+    let mut result = SendResult::new(target);
+    // 接收方只接受已注册设备的请求
+    match register(setting, &target).await {
+        Ok(device) => {
+            result.resumable = device.resumable == Some(true);
+            result.v1 = device.is_v1();
+            result.peer = Some(device);
+        }
+        Err(e) => log::warn!("register to {target} error: {e}"),
+    }
+    let file_req = FileRequest {
+        info: setting.to_device_message(None),
+        files: files
+            .iter()
+            .map(|(_, info)| (info.id.clone(), info.clone()))
+            .collect(),
+    };
+    // 错误类型不是 `Send`，先转成字符串
+    let resp = match result.v1 {
+        true => v1::prepare_upload(file_req, &target).await,
+        false => prepare_upload(file_req, &target, pin).await,
+    }
+    .map_err(|e| e.to_string());
+    let resp = match resp {
+        Ok(resp) if resp.files.is_empty() => {
+            result.error = Some("declined by receiver".to_string());
+            result.declined = true;
+            return finish(result, &events).await;
+        }
+        Ok(resp) => resp,
+        Err(e) => {
+            result.error = Some(e);
+            return finish(result, &events).await;
+        }
+    };
+    result.session_id = Some(resp.session_id);
+    result.accepted = resp.files.keys().cloned().collect();
+    result.tokens = resp.files;
+    let ids = result.accepted.iter().cloned().collect();
+    upload_files(setting, result, ids, files, events, false).await
+}
+
+// 重新上传失败的文件，沿用原来的会话和 token，对方支持时从断开的位置续传
+pub async fn retry_failed(
+    setting: &ServerSetting,
+    mut previous: SendResult,
+    files: &[(PathBuf, FileInfo)],
+    events: mpsc::Sender<SendEvent>,
+) -> SendResult {
+    let ids = std::mem::take(&mut previous.failed).into_keys().collect();
+    upload_files(setting, previous, ids, files, events, true).await
+}
+
+// 并行上传 `ids` 中的文件，最多同时上传 `max_parallel_uploads` 个
+async fn upload_files(
+    setting: &ServerSetting,
+    mut result: SendResult,
+    ids: HashSet<String>,
+    files: &[(PathBuf, FileInfo)],
+    events: mpsc::Sender<SendEvent>,
+    resume: bool,
+) -> SendResult {
+    let target = result.target;
+    let resumable = result.resumable;
+    let v1 = result.v1;
+    let Some(session_id) = result.session_id.clone() else {
+        return finish(result, &events).await;
+    };
+    // 重试时再次发出，`file_ids` 为这次上传的文件
+    let _ = events
+        .send(SendEvent::Accepted {
+            target,
+            session_id: session_id.clone(),
+            file_ids: ids.iter().cloned().collect(),
+        })
+        .await;
+
+    let permits = Arc::new(Semaphore::new(setting.max_parallel_uploads.max(1)));
+    let retries = setting.upload_retries;
+    let mut uploads = JoinSet::new();
+    for (path, info) in files.iter().filter(|(_, info)| ids.contains(&info.id)) {
+        let Some(token) = result.tokens.get(&info.id) else {
+            continue;
+        };
+        let upload_param = UploadParam {
+            session_id: session_id.clone(),
+            file_id: info.id.clone(),
+            token: token.clone(),
+            offset: None,
+        };
+        let path = path.clone();
+        let file_id = info.id.clone();
+        let events = events.clone();
+        let permits = permits.clone();
+        uploads.spawn(async move {
+            let _permit = permits.acquire_owned().await;
+            let mut attempt = 0;
+            let error = loop {
+                let mut upload_param = upload_param.clone();
+                if resumable && (resume || attempt > 0) {
+                    let offset = upload_offset(&upload_param, &target).await;
+                    upload_param.offset = offset.ok().filter(|offset| *offset > 0);
+                }
+                match upload_with_progress(upload_param, &path, target, v1, &events).await {
+                    Ok(()) => break None,
+                    // 临时错误且 token 仍然有效时重试
+                    Err(e) if attempt < retries && is_transient(&*e) => {
+                        attempt += 1;
+                        let error = e.to_string();
+                        let delay = backoff(attempt);
+                        log::info!(
+                            "upload {file_id} to {target} error: {error}, retry in {delay:?}"
+                        );
+                        let _ = events
+                            .send(SendEvent::Retrying {
+                                target,
+                                file_id: file_id.clone(),
+                                attempt,
+                                error,
+                            })
+                            .await;
+                        tokio::time::sleep(delay).await;
+                    }
+                    Err(e) => break Some(e.to_string()),
+                }
+            };
+            let _ = events
+                .send(SendEvent::FileFinished {
+                    target,
+                    file_id: file_id.clone(),
+                    error: error.clone(),
+                })
+                .await;
+            (file_id, error)
+        });
+    }
+    while let Some(res) = uploads.join_next().await {
+        match res {
+            Ok((file_id, None)) => result.finished.push(file_id),
+            Ok((file_id, Some(e))) => {
+                log::error!("upload {file_id} to {target} error: {e}");
+                result.failed.insert(file_id, e);
+            }
+            Err(e) => log::error!("upload task error: {e}"),
+        }
+    }
+    finish(result, &events).await
+}
+
+async fn upload_with_progress(
+    upload_param: UploadParam,
+    path: &PathBuf,
+    target: SocketAddr,
+    v1: bool,
+    events: &mpsc::Sender<SendEvent>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let file_id = upload_param.file_id.clone();
+    let (progress_tx, mut progress_rx) = watch::channel(0);
+    let forward = async move {
+        while progress_rx.changed().await.is_ok() {
+            let bytes = *progress_rx.borrow_and_update();
+            let _ = events
+                .send(SendEvent::Progress {
+                    target,
+                    file_id: file_id.clone(),
+                    bytes,
+                })
+                .await;
+        }
+    };
+    let upload = async {
+        match v1 {
+            true => v1::upload(upload_param, path, &target, Some(progress_tx)).await,
+            false => upload(upload_param, path, &target, Some(progress_tx)).await,
+        }
+    };
+    let (res, _) = tokio::join!(upload, forward);
+    res
+}
+
+// 连接失败、超时、传输中断以及网关错误可以重试，403/409 等说明会话已经失效
+fn is_transient(e: &(dyn Error + Send + Sync + 'static)) -> bool {
+    let Some(e) = e.downcast_ref::<reqwest::Error>() else {
+        return false;
+    };
+    match e.status() {
+        Some(status) => matches!(status.as_u16(), 408 | 429 | 502 | 503 | 504),
+        None => e.is_connect() || e.is_timeout() || e.is_request() || e.is_body(),
+    }
+}
+
+// 第 `attempt` 次重试前的等待时间：0.5s、1s、2s……最多 30s
+fn backoff(attempt: u32) -> Duration {
+    let delay = RETRY_BASE_DELAY.saturating_mul(1 << attempt.saturating_sub(1).min(16));
+    delay.min(RETRY_MAX_DELAY)
+}
+
+async fn finish(result: SendResult, events: &mpsc::Sender<SendEvent>) -> SendResult {
+    let _ = events
+        .send(SendEvent::Finished {
+            result: result.clone(),
+        })
+        .await;
+    result
+}
+
+// 同时发送给多个设备，每个设备有独立的会话，按 `targets` 的顺序返回结果
+// 重复的地址只发送一次，结果也相同
+pub async fn send_to_many(
+    setting: ServerSetting,
+    targets: Vec<SocketAddr>,
+    files: Vec<(PathBuf, FileInfo)>,
+    pin: Option<String>,
+    events: mpsc::Sender<SendEvent>,
+) -> Vec<SendResult> {
+    let mut unique = HashSet::new();
+    let mut sessions = JoinSet::new();
+    for target in targets.iter().copied() {
+        if !unique.insert(target) {
+            continue;
+        }
+        let setting = setting.clone();
+        let files = files.clone();
+        let pin = pin.clone();
+        let events = events.clone();
+        sessions.spawn(async move {
+            send_files(&setting, target, &files, pin.as_deref(), events).await
+        });
+    }
+    let mut results = HashMap::new();
+    while let Some(res) = sessions.join_next().await {
+        match res {
+            Ok(result) => {
+                results.insert(result.target, result);
+            }
+            Err(e) => log::error!("send task error: {e}"),
+        }
+    }
+    targets
+        .into_iter()
+        .map(|target| {
+            results.get(&target).cloned().unwrap_or_else(|| {
+                let mut result = SendResult::new(target);
+                result.error = Some("send task failed".to_string());
+                result
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::from_millis(500));
+        assert_eq!(backoff(2), Duration::from_secs(1));
+        assert_eq!(backoff(4), Duration::from_secs(4));
+        assert_eq!(backoff(10), RETRY_MAX_DELAY);
+        assert_eq!(backoff(u32::MAX), RETRY_MAX_DELAY);
+        let io_error: Box<dyn Error + Send + Sync> = Box::new(std::io::Error::other("disk"));
+        assert!(!is_transient(&*io_error));
+    }
+
+    #[tokio::test]
+    async fn test_send_to_duplicate_targets() {
+        // 没有服务监听的端口，连接会立即失败
+        let target: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let (tx, mut rx) = mpsc::channel(64);
+        let setting = ServerSetting {
+            upload_retries: 0,
+            ..Default::default()
+        };
+        let results = send_to_many(setting, vec![target, target], vec![], None, tx).await;
+        assert_eq!(results.len(), 2);
+        for result in &results {
+            assert_eq!(result.target, target);
+            assert!(result.error.is_some());
+            assert_ne!(result.error.as_deref(), Some("send task failed"));
+        }
+        // 只建立了一个会话
+        let mut finished = 0;
+        while let Some(event) = rx.recv().await {
+            if matches!(event, SendEvent::Finished { .. }) {
+                finished += 1;
+            }
+        }
+        assert_eq!(finished, 1);
+    }
+}
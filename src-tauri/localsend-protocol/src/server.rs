@@ -1,21 +1,56 @@
 use std::{
     collections::{HashMap, HashSet},
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
-    path::PathBuf,
-    sync::Arc,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
-use axum::{routing::post, Router};
-use tokio::sync::{mpsc, oneshot, watch, RwLock};
+use axum::{
+    routing::{get, post},
+    Router,
+};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, oneshot, watch, Mutex, RwLock},
+    time,
+};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
 
 use crate::{
     api::*,
-    mission::Mission,
-    model::{DeviceMessage, DeviceType, FileInfo, FileRequest, Protocol, UploadParam},
-    multicast::{multicast_listener, multicast_message},
+    guard::{Admission, Endpoint, RateLimit, RateLimiter},
+    history::now_millis,
+    interface::{
+        default_excluded_interfaces, resolve_bind_addr, select_interfaces, InterfaceMatcher,
+    },
+    mission::{load_missions, write_missions, Mission, MissionState, MissionSummary},
+    model::{
+        DeviceMessage, DeviceType, DownloadResponse, FileInfo, FileRequest, PrepareDownloadParam,
+        Protocol, UploadParam,
+    },
+    multicast::{bind_multicast, multicast_listener, multicast_message},
+    policy::{AcceptPolicy, PolicyAction},
+    route::{resolve_destination, RouteRule},
+    share::{self, Share, ShareError},
+    throttle::global_throttle,
+    v1,
 };
 
-#[derive(Clone, Debug)]
+const MISSION_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+// 组播接收连续出错时逐渐延长等待，超过次数后重新绑定
+const MULTICAST_RETRY_DELAY: Duration = Duration::from_millis(100);
+const MULTICAST_MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+const MULTICAST_MAX_ERRORS: u32 = 10;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
 pub struct ServerSetting {
     pub alias: String,
     pub device_model: Option<String>,
@@ -23,10 +58,31 @@ pub struct ServerSetting {
     pub protocol: Option<Protocol>,
     pub download: bool,
     pub port: u16,
-    pub interface_addr: String,
+    pub port_fallback: u16,              // 端口被占用时向后尝试的端口数量
+    pub interface_addr: String,          // HTTP 服务绑定的地址：`0.0.0.0`、IP、网卡名或 CIDR
+    pub interfaces: Vec<String>, // 收发组播消息的网卡：网卡名（支持 `*`）或 CIDR，为空则使用全部网卡
+    pub exclude_interfaces: Vec<String>, // 排除的网卡，优先于 `interfaces`
     pub multicast_addr: String,
+    pub multicast_port: u16,
     pub store_path: PathBuf,
     pub fingerprint: String,
+    pub pin: Option<String>, // 设置后发送方需要在 prepare-upload 时带上 PIN
+    pub accept_policy: AcceptPolicy, // 询问之前先按规则自动同意或拒绝文件传入请求
+    pub blocked_devices: Vec<String>, // 屏蔽的设备指纹
+    pub blocked_ips: Vec<IpAddr>, // 屏蔽的 IP
+    pub rate_limit: RateLimit, // register、prepare-upload 和 prepare-download 的限流
+    pub max_parallel_uploads: usize, // 每个会话同时上传的文件数
+    pub bandwidth_limit: Option<u64>, // 发送和接收合计的带宽上限（字节/秒）
+    pub upload_retries: u32, // 上传遇到临时错误时的重试次数
+    #[serde(skip)]
+    pub session_file: Option<PathBuf>, // 保存接收任务的文件，重启后发送方可以续传，运行时设置，不保存
+    pub session_timeout: u64, // 接收任务空闲多久后过期（秒），过期后不能再续传
+    pub max_file_size: Option<u64>, // 接收的单个文件大小上限（字节）
+    pub max_session_size: Option<u64>, // 一次接收的文件总大小上限（字节）
+    pub disk_space_margin: u64, // 接收后保存目录所在磁盘至少剩余的空间（字节）
+    pub routes: Vec<RouteRule>, // 按发送方和文件类型选择保存目录，都不匹配时保存到 `store_path`
+    #[serde(skip)]
+    pub bound_port: Option<u16>, // 实际绑定的端口，可能是 `port` 之后的备用端口，只在运行时有值，不保存
 }
 
 impl ServerSetting {
@@ -37,11 +93,211 @@ impl ServerSetting {
             device_model: self.device_model.clone(),
             device_type: self.device_type.clone(),
             fingerprint: self.fingerprint.clone(),
-            port: Some(self.port),
+            port: Some(self.listening_port()),
             protocol: self.protocol.clone(),
             download: self.download,
             announce,
+            resumable: Some(true),
+        }
+    }
+
+    // 告诉其他设备的端口：已绑定时为实际端口，否则为设置的端口
+    pub fn listening_port(&self) -> u16 {
+        self.bound_port.unwrap_or(self.port)
+    }
+
+    pub fn multicast_interfaces(&self) -> Vec<Ipv4Addr> {
+        select_interfaces(&self.interfaces, &self.exclude_interfaces)
+            .into_iter()
+            .map(|iface| iface.ip)
+            .collect()
+    }
+
+    pub fn bind_addr(&self) -> Result<SocketAddrV4, String> {
+        let ip = resolve_bind_addr(&self.interface_addr)
+            .ok_or(format!("no interface matches `{}`", self.interface_addr))?;
+        Ok(SocketAddrV4::new(ip, self.port))
+    }
+
+    pub fn multicast_socket_addr(&self) -> SocketAddrV4 {
+        format!("{}:{}", self.multicast_addr, self.multicast_port)
+            .parse::<SocketAddrV4>()
+            .unwrap_or(SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 167), 53317))
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr, fingerprint: Option<&str>) -> bool {
+        self.blocked_ips.contains(&ip)
+            || fingerprint.is_some_and(|f| self.blocked_devices.iter().any(|b| b == f))
+    }
+
+    // 这些设置改变后需要重新绑定 HTTP 服务和组播
+    pub fn needs_rebind(&self, other: &ServerSetting) -> bool {
+        self.port != other.port
+            || self.port_fallback != other.port_fallback
+            || self.protocol != other.protocol
+            || self.interface_addr != other.interface_addr
+            || self.interfaces != other.interfaces
+            || self.exclude_interfaces != other.exclude_interfaces
+            || self.multicast_addr != other.multicast_addr
+            || self.multicast_port != other.multicast_port
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.alias.trim().is_empty() {
+            return Err("alias must not be empty".to_string());
+        }
+        if self.fingerprint.is_empty() {
+            return Err("fingerprint must not be empty".to_string());
+        }
+        if self.port == 0 || self.multicast_port == 0 {
+            return Err("port must not be 0".to_string());
+        }
+        if !self.store_path.is_dir() {
+            return Err(format!(
+                "store path {:?} is not a directory",
+                self.store_path
+            ));
+        }
+        match self.multicast_addr.parse::<Ipv4Addr>() {
+            Ok(ip) if ip.is_multicast() => {}
+            _ => {
+                return Err(format!(
+                    "invalid multicast address `{}`",
+                    self.multicast_addr
+                ))
+            }
+        }
+        if self.max_parallel_uploads == 0 {
+            return Err("max parallel uploads must not be 0".to_string());
+        }
+        if self.session_timeout == 0 {
+            return Err("session timeout must not be 0".to_string());
+        }
+        if self.max_file_size == Some(0) || self.max_session_size == Some(0) {
+            return Err("max size must not be 0".to_string());
+        }
+        if self.bandwidth_limit == Some(0) {
+            return Err("bandwidth limit must not be 0".to_string());
+        }
+        if let Some(pin) = &self.pin {
+            if pin.is_empty() {
+                return Err("pin must not be empty".to_string());
+            }
+        }
+        for rule in &self.routes {
+            rule.validate()?;
+        }
+        for pattern in self.interfaces.iter().chain(self.exclude_interfaces.iter()) {
+            pattern.parse::<InterfaceMatcher>()?;
+        }
+        self.bind_addr()?;
+        Ok(())
+    }
+
+    // 检查 prepare-upload 中声明的文件大小
+    pub fn check_size(&self, sizes: &[u64]) -> Result<(), String> {
+        if let (Some(max), Some(size)) = (self.max_file_size, sizes.iter().max()) {
+            if *size > max {
+                return Err(format!("file size {size} exceeds limit of {max} bytes"));
+            }
         }
+        let total = sizes
+            .iter()
+            .fold(0u64, |total, size| total.saturating_add(*size));
+        match self.max_session_size {
+            Some(max) if total > max => {
+                Err(format!("total size {total} exceeds limit of {max} bytes"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // 保存目录所在磁盘的可用空间，无法获取时为 None
+    pub fn free_space(&self) -> Option<u64> {
+        fs4::available_space(&self.store_path)
+            .map_err(|e| log::warn!("get free space of {:?} error: {e}", self.store_path))
+            .ok()
+    }
+
+    // 每个文件按路由规则保存到各自的目录，同一磁盘上的文件大小合计后检查：
+    // 接收后仍要保留 `disk_space_margin`，无法获取可用空间时不检查
+    pub fn check_disk_space(&self, file_req: &FileRequest) -> Result<(), String> {
+        let now = Local::now();
+        let mut volumes: HashMap<String, (PathBuf, u64)> = HashMap::new();
+        for file in file_req.files.values() {
+            let dir =
+                resolve_destination(&self.routes, &self.store_path, &file_req.info, file, now);
+            // 目录在接收时才创建，使用已存在的上级目录
+            let dir = existing_ancestor(&dir);
+            let (_, size) = volumes.entry(volume_id(&dir)).or_insert_with(|| (dir, 0));
+            *size = size.saturating_add(file.size);
+        }
+        for (dir, size) in volumes.into_values() {
+            match fs4::available_space(&dir) {
+                Ok(free) => check_free_space(size, free, self.disk_space_margin)
+                    .map_err(|e| format!("{e} in {dir:?}"))?,
+                Err(e) => log::warn!("get free space of {dir:?} error: {e}"),
+            }
+        }
+        Ok(())
+    }
+
+    // 依次尝试 `port..=port + port_fallback`，返回实际绑定的 listener
+    pub async fn bind_http_listener(&self) -> Result<TcpListener, Box<dyn std::error::Error>> {
+        let addr = self.bind_addr()?;
+        let last_port = self.port.saturating_add(self.port_fallback);
+        let mut last_err = None;
+        for port in self.port..=last_port {
+            match TcpListener::bind(SocketAddrV4::new(*addr.ip(), port)).await {
+                Ok(listener) => return Ok(listener),
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                    log::warn!("port {port} is in use, try next one");
+                    last_err = Some(e);
+                }
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
+        Err(match last_err {
+            Some(e) => Box::new(e),
+            None => format!("no port available in {}..={last_port}", self.port).into(),
+        })
+    }
+}
+
+fn existing_ancestor(path: &Path) -> PathBuf {
+    path.ancestors()
+        .find(|dir| dir.is_dir())
+        .unwrap_or(Path::new("."))
+        .to_path_buf()
+}
+
+// 区分目录所在的磁盘，无法获取时每个目录单独计算
+#[cfg(unix)]
+fn volume_id(dir: &Path) -> String {
+    use std::os::unix::fs::MetadataExt;
+
+    match std::fs::metadata(dir) {
+        Ok(metadata) => metadata.dev().to_string(),
+        Err(_) => dir.to_string_lossy().to_string(),
+    }
+}
+
+#[cfg(not(unix))]
+fn volume_id(dir: &Path) -> String {
+    // Windows 上按盘符区分
+    let dir = std::fs::canonicalize(dir).unwrap_or(dir.to_path_buf());
+    match dir.components().next() {
+        Some(prefix) => prefix.as_os_str().to_string_lossy().to_string(),
+        None => dir.to_string_lossy().to_string(),
+    }
+}
+
+fn check_free_space(size: u64, free: u64, margin: u64) -> Result<(), String> {
+    match size.saturating_add(margin) > free {
+        true => Err(format!(
+            "not enough disk space: {size} bytes needed with {margin} bytes reserved, {free} bytes available"
+        )),
+        false => Ok(()),
     }
 }
 
@@ -54,31 +310,71 @@ impl Default for ServerSetting {
             protocol: Some(Protocol::Http),
             download: false,
             port: 53317,
+            port_fallback: 10,
             interface_addr: "0.0.0.0".to_string(),
+            interfaces: vec![],
+            exclude_interfaces: default_excluded_interfaces(),
             multicast_addr: "224.0.0.167".to_string(),
+            multicast_port: 53317,
             store_path: PathBuf::new(),
             fingerprint: "".to_string(),
+            pin: None,
+            accept_policy: AcceptPolicy::default(),
+            blocked_devices: vec![],
+            blocked_ips: vec![],
+            rate_limit: RateLimit::default(),
+            max_parallel_uploads: 4,
+            bandwidth_limit: None,
+            upload_retries: 3,
+            session_file: None,
+            session_timeout: 3600,
+            max_file_size: None,
+            max_session_size: None,
+            disk_space_margin: 64 * 1024 * 1024,
+            routes: vec![],
+            bound_port: None,
         }
     }
 }
 
 pub struct ServerState {
-    setting: ServerSetting,
+    setting: RwLock<ServerSetting>,
     devices: RwLock<HashMap<String, (SocketAddr, DeviceMessage)>>,
     misssions: RwLock<HashMap<String, Mission>>,
+    missions_version: AtomicU64, // 任务快照的序号，在任务写锁内递增
+    saved_version: Mutex<u64>,   // 已写入文件的快照序号，旧快照不会覆盖新快照
+    limiter: RwLock<RateLimiter>,
+    share: RwLock<Option<Share>>,
     sender: mpsc::Sender<ServerMessage>, // 从 Server 发出消息
     receiver: RwLock<mpsc::Receiver<OutMessage>>, // 从外部接受消息
+    rebind: watch::Sender<()>,           // 网络相关设置改变，通知重新绑定
+    stopping: CancellationToken,         // 停止接受新的连接
+    cancel: CancellationToken,           // 结束所有任务
 }
 
 pub enum ServerMessage {
     DeviceConnect(SocketAddr, DeviceMessage), // 设备连接
-    FilePrepareUpload(FileRequest, oneshot::Sender<HashSet<String>>), // 文件传入请求，发回同意文件传入的File id Set
-    Progress(String, watch::Receiver<usize>),                         // 某个文件id的下载进度条
-    CancelMission(Option<Mission>),                                   // 任务被取消
+    FilePrepareUpload(FileRequest, Option<u64>, oneshot::Sender<HashSet<String>>), // 文件传入请求和保存目录的可用空间，发回同意文件传入的File id Set
+    Progress(String, watch::Receiver<usize>), // 某个文件id的下载进度条
+    CancelMission(Option<Mission>),           // 任务被取消
+    Listening(SocketAddr),                    // HTTP 服务实际监听的地址（端口可能因占用而改变）
+    FileAutoAccepted(FileRequest),            // 文件传入请求已被自动同意
+    FileAutoDeclined(FileRequest),            // 文件传入请求已被自动拒绝
+    FileDeclined(FileRequest, String), // 同意后因为错误（例如磁盘空间不足）拒绝，只包含同意的文件
+    MissionStarted(Mission),           // 新建下载任务，之后可以用任务 id 取消
+    TextReceived(DeviceMessage, String), // 收到文本消息，不会保存为文件
+    UploadFinished(String, String, Result<PathBuf, String>), // 任务 id、文件 id、保存的位置或错误
+    MissionFinished(MissionSummary),   // 任务完成、被取消或过期，之后会从任务列表中移除
+    ShareAccess(IpAddr, Option<String>, oneshot::Sender<bool>), // 浏览器请求访问分享的文件（IP 和 User-Agent），发回是否同意
 }
 
+#[allow(clippy::large_enum_variant)]
 pub enum OutMessage {
-    Refresh, // 重新发送一次组播消息
+    Refresh,                       // 重新发送一次组播消息
+    UpdateSettings(ServerSetting), // 更新设置，必要时重新绑定端口
+    Shutdown(Option<Duration>),    // 关闭服务器，Some 时在期限内等待正在进行的传输
+    CancelMission(String),         // 接收方取消任务
+    Share(Option<Share>),          // 开始或停止分享，停止后已同意的访问也失效
 }
 
 pub enum InnerMessage {
@@ -86,23 +382,42 @@ pub enum InnerMessage {
     AddDevice(String, SocketAddr, DeviceMessage),
     GetDevice(String, oneshot::Sender<Option<DeviceMessage>>),
     FilePrepareUpload(FileRequest, oneshot::Sender<HashSet<String>>),
+    ReceiveText(FileRequest, oneshot::Sender<bool>),
     AddMission(String, Mission),
     GetMission(String, oneshot::Sender<Option<Mission>>),
+    ListMissions(oneshot::Sender<Vec<Mission>>),
     GetFileInfo(
         UploadParam,
         oneshot::Sender<Option<(FileInfo, watch::Sender<usize>)>>,
     ),
     GetStorePath(oneshot::Sender<PathBuf>),
+    GetDestination(String, String, oneshot::Sender<PathBuf>),
+    UploadFinished(String, String, Result<PathBuf, String>),
+    GetPin(oneshot::Sender<Option<String>>),
+    CheckSize(Vec<u64>, oneshot::Sender<Result<(), String>>),
+    CheckDiskSpace(FileRequest, oneshot::Sender<Result<(), String>>),
+    Admit(IpAddr, Option<String>, Endpoint, oneshot::Sender<Admission>),
+    PrepareDownload(
+        IpAddr,
+        Option<String>,
+        PrepareDownloadParam,
+        oneshot::Sender<Result<DownloadResponse, ShareError>>,
+    ),
+    GetShare(oneshot::Sender<Option<Share>>),
     CancelMission(String),
+    Shutdown(Option<Duration>),
 }
 
 pub struct Server {
     state: Arc<ServerState>,
+    handle: ServerHandle,
+    inner_receiver: RwLock<Option<mpsc::Receiver<InnerMessage>>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct ServerHandle {
     inner_sender: mpsc::Sender<InnerMessage>, // Http Server 发出的内部信息
+    cancel: CancellationToken,                // 服务器关闭时取消正在进行的传输
 }
 
 impl ServerHandle {
@@ -110,10 +425,7 @@ impl ServerHandle {
         let (tx, rx) = oneshot::channel();
         let _ = self.inner_sender.send(InnerMessage::GetMyself(tx)).await;
 
-        match rx.await {
-            Err(_) => None,
-            Ok(device) => Some(device),
-        }
+        rx.await.ok()
     }
 
     pub async fn insert_device(
@@ -147,6 +459,16 @@ impl ServerHandle {
         rx.await.unwrap_or_default()
     }
 
+    // 返回 false 表示被拒绝
+    pub async fn receive_text(&self, file_req: FileRequest) -> bool {
+        let (tx, rx) = oneshot::channel();
+        let _ = self
+            .inner_sender
+            .send(InnerMessage::ReceiveText(file_req, tx))
+            .await;
+        rx.await.unwrap_or_default()
+    }
+
     pub async fn insert_mission(&self, mission_id: String, mission: Mission) {
         let _ = self
             .inner_sender
@@ -164,6 +486,13 @@ impl ServerHandle {
         rx.await.unwrap_or_default()
     }
 
+    pub async fn list_missions(&self) -> Vec<Mission> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.inner_sender.send(InnerMessage::ListMissions(tx)).await;
+
+        rx.await.unwrap_or_default()
+    }
+
     pub async fn get_file_info(
         &self,
         param: UploadParam,
@@ -177,6 +506,18 @@ impl ServerHandle {
         rx.await.unwrap_or_default()
     }
 
+    pub async fn upload_finished(
+        &self,
+        session_id: String,
+        file_id: String,
+        result: Result<PathBuf, String>,
+    ) {
+        let _ = self
+            .inner_sender
+            .send(InnerMessage::UploadFinished(session_id, file_id, result))
+            .await;
+    }
+
     pub async fn get_store_path(&self) -> PathBuf {
         let (tx, rx) = oneshot::channel();
         let _ = self.inner_sender.send(InnerMessage::GetStorePath(tx)).await;
@@ -187,6 +528,92 @@ impl ServerHandle {
         }
     }
 
+    // 按路由规则得到任务中某个文件的保存目录
+    pub async fn get_destination(&self, session_id: String, file_id: String) -> PathBuf {
+        let (tx, rx) = oneshot::channel();
+        let _ = self
+            .inner_sender
+            .send(InnerMessage::GetDestination(session_id, file_id, tx))
+            .await;
+
+        rx.await.unwrap_or_default()
+    }
+
+    pub async fn get_pin(&self) -> Option<String> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.inner_sender.send(InnerMessage::GetPin(tx)).await;
+
+        rx.await.unwrap_or_default()
+    }
+
+    pub async fn check_size(&self, sizes: Vec<u64>) -> Result<(), String> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self
+            .inner_sender
+            .send(InnerMessage::CheckSize(sizes, tx))
+            .await;
+
+        rx.await.unwrap_or(Ok(()))
+    }
+
+    // 检查同意的文件能否放进保存目录
+    pub async fn check_disk_space(&self, file_req: FileRequest) -> Result<(), String> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self
+            .inner_sender
+            .send(InnerMessage::CheckDiskSpace(file_req, tx))
+            .await;
+
+        rx.await.unwrap_or(Ok(()))
+    }
+
+    // 检查屏蔽列表和限流
+    pub async fn admit(
+        &self,
+        ip: IpAddr,
+        fingerprint: Option<String>,
+        endpoint: Endpoint,
+    ) -> Admission {
+        let (tx, rx) = oneshot::channel();
+        let _ = self
+            .inner_sender
+            .send(InnerMessage::Admit(ip, fingerprint, endpoint, tx))
+            .await;
+
+        rx.await.unwrap_or(Admission::Allowed)
+    }
+
+    // 检查 token 或 PIN，新的访问需要外部同意
+    pub async fn prepare_download(
+        &self,
+        ip: IpAddr,
+        user_agent: Option<String>,
+        param: PrepareDownloadParam,
+    ) -> Result<DownloadResponse, ShareError> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self
+            .inner_sender
+            .send(InnerMessage::PrepareDownload(ip, user_agent, param, tx))
+            .await;
+
+        rx.await.unwrap_or(Err(ShareError::NotSharing))
+    }
+
+    pub async fn get_share(&self) -> Option<Share> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.inner_sender.send(InnerMessage::GetShare(tx)).await;
+
+        rx.await.unwrap_or_default()
+    }
+
+    pub fn cancelled(&self) -> WaitForCancellationFuture<'_> {
+        self.cancel.cancelled()
+    }
+
+    pub async fn shutdown(&self, drain: Option<Duration>) {
+        let _ = self.inner_sender.send(InnerMessage::Shutdown(drain)).await;
+    }
+
     pub async fn cancel_mission(&self, mission_id: String) {
         let _ = self
             .inner_sender
@@ -201,101 +628,252 @@ impl Server {
         receiver: mpsc::Receiver<OutMessage>,
     ) -> (Self, mpsc::Receiver<ServerMessage>) {
         let (tx, rx) = mpsc::channel(8);
+        let (itx, irx) = mpsc::channel(8);
+        let cancel = CancellationToken::new();
+        let missions = match &setting.session_file {
+            Some(path) if path.exists() => load_missions(path).unwrap_or_else(|e| {
+                log::error!("load missions from {path:?} error: {e}");
+                HashMap::new()
+            }),
+            _ => HashMap::new(),
+        };
         (
             Self {
                 state: Arc::new(ServerState {
                     sender: tx,
-                    setting,
+                    setting: RwLock::new(setting),
                     devices: RwLock::new(HashMap::new()),
-                    misssions: RwLock::new(HashMap::new()),
+                    misssions: RwLock::new(missions),
+                    missions_version: AtomicU64::new(0),
+                    saved_version: Mutex::new(0),
+                    limiter: RwLock::new(RateLimiter::default()),
+                    share: RwLock::new(None),
                     receiver: RwLock::new(receiver),
+                    rebind: watch::channel(()).0,
+                    stopping: CancellationToken::new(),
+                    cancel: cancel.clone(),
                 }),
+                handle: ServerHandle {
+                    inner_sender: itx,
+                    cancel,
+                },
+                inner_receiver: RwLock::new(Some(irx)),
             },
             rx,
         )
     }
 
+    // 用于在 `start()` 之外关闭服务器
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
     pub async fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
-        // 发送组播消息
-        self.state.handle_out_message(OutMessage::Refresh).await;
+        let mut irx = self
+            .inner_receiver
+            .write()
+            .await
+            .take()
+            .ok_or("server already started")?;
+        // 先绑定端口，组播消息中需要带上实际端口
+        let listener = self.state.setting.read().await.bind_http_listener().await?;
+        global_throttle().set_limit(self.state.setting.read().await.bandwidth_limit);
+        let cancel = self.state.cancel.clone();
+        let mut tasks = vec![];
 
-        // 监听组播
-        let state1 = self.state.clone();
-        let recv_addr = format!(
-            "{}:{}",
-            self.state.setting.multicast_addr, self.state.setting.port
-        )
-        .parse::<SocketAddrV4>()
-        .unwrap_or(SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 167), 53317));
-        tokio::spawn(async move {
+        // 监听组播，网络设置改变后重新绑定
+        let state = self.state.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut rebind_rx = state.rebind.subscribe();
             loop {
-                let (device_message, sender_addr) = match multicast_listener(&recv_addr).await {
-                    Ok(msg) => msg,
+                let (recv_addr, interfaces) = {
+                    let setting = state.setting.read().await;
+                    (
+                        setting.multicast_socket_addr(),
+                        setting.multicast_interfaces(),
+                    )
+                };
+                log::info!("multicast interfaces: {interfaces:?}");
+                let socket = match bind_multicast(&recv_addr, &interfaces) {
+                    Ok(socket) => socket,
                     Err(e) => {
-                        // 一般是由于已经有在监听的设备了，终止监听
-                        log::error!("Error multicast listening: {}", e);
-                        return;
+                        log::error!("Error binding multicast socket: {}", e);
+                        tokio::select! {
+                            _ = state.cancel.cancelled() => return,
+                            _ = rebind_rx.changed() => continue,
+                        }
                     }
                 };
-                let mut devices = state1.devices.write().await;
-                // 过滤已经存在的设备
-                if !devices.contains_key(&device_message.fingerprint) {
-                    devices.insert(
-                        device_message.fingerprint.to_owned(),
-                        (sender_addr, device_message.clone()),
-                    );
-                    state1
-                        .sender
-                        .send(ServerMessage::DeviceConnect(sender_addr, device_message))
-                        .await
-                        .unwrap();
+                let mut errors = 0;
+                loop {
+                    tokio::select! {
+                        _ = state.cancel.cancelled() => return,
+                        _ = rebind_rx.changed() => break,
+                        res = multicast_listener(&socket) => match res {
+                            Ok((device_message, sender_addr)) => {
+                                errors = 0;
+                                state.handle_multicast(device_message, sender_addr).await;
+                            }
+                            Err(e) => {
+                                log::error!("Error multicast listening: {}", e);
+                                errors += 1;
+                                if errors >= MULTICAST_MAX_ERRORS {
+                                    log::warn!("too many multicast errors, rebinding");
+                                    break;
+                                }
+                                let delay = MULTICAST_RETRY_DELAY
+                                    .saturating_mul(1 << errors.min(6))
+                                    .min(MULTICAST_MAX_RETRY_DELAY);
+                                tokio::select! {
+                                    _ = state.cancel.cancelled() => return,
+                                    _ = rebind_rx.changed() => break,
+                                    _ = time::sleep(delay) => {}
+                                }
+                            }
+                        }
+                    }
                 }
             }
-        });
+        }));
 
         // 监听服务器内部消息
-        let (itx, mut irx) = mpsc::channel(8);
         let state = self.state.clone();
-        tokio::spawn(async move {
+        tasks.push(tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = state.cancel.cancelled() => break,
+                    message = irx.recv() => match message {
+                        Some(message) => tokio::select! {
+                            _ = state.cancel.cancelled() => break,
+                            _ = state.handle_inner_message(message) => {}
+                        },
+                        None => break,
+                    }
+                }
+            }
+        }));
+
+        // 定期清理过期的接收任务
+        let state = self.state.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut interval = time::interval(MISSION_CLEANUP_INTERVAL);
             loop {
-                if let Some(message) = irx.recv().await {
-                    state.handle_inner_message(message).await;
+                tokio::select! {
+                    _ = state.cancel.cancelled() => break,
+                    _ = interval.tick() => state.expire_missions().await,
                 }
             }
-        });
+        }));
 
         // 监听服务器外部消息
         let state = self.state.clone();
-        tokio::spawn(async move {
+        tasks.push(tokio::spawn(async move {
+            let mut receiver = state.receiver.write().await;
             loop {
-                if let Some(message) = state.receiver.write().await.recv().await {
-                    state.handle_out_message(message).await;
+                tokio::select! {
+                    _ = state.cancel.cancelled() => break,
+                    message = receiver.recv() => match message {
+                        Some(message) => state.handle_out_message(message).await,
+                        None => break,
+                    }
                 }
             }
-        });
+        }));
 
         // http_server
         let http_server = Router::new()
+            .route("/api/localsend/v2/info", get(handle_info))
             .route("/api/localsend/v2/register", post(handle_register))
             .route(
                 "/api/localsend/v2/prepare-upload",
                 post(handle_prepare_upload),
             )
             .route("/api/localsend/v2/upload", post(handle_upload))
+            .route("/api/localsend/v2/upload-offset", get(handle_upload_offset))
             .route("/api/localsend/v2/cancel", post(handel_cancel))
+            .route(
+                "/api/localsend/v2/prepare-download",
+                post(share::handle_prepare_download),
+            )
+            .route("/api/localsend/v2/download", get(share::handle_download))
+            .route(
+                "/api/localsend/v2/download-zip",
+                get(share::handle_download_zip),
+            )
+            // 分享时给浏览器用的网页
+            .route("/", get(share::handle_page))
+            // 兼容只支持 v1 的旧版本
+            .route("/api/localsend/v1/info", get(handle_info))
+            .route("/api/localsend/v1/register", post(handle_register))
+            .route(
+                "/api/localsend/v1/send-request",
+                post(v1::handle_send_request),
+            )
+            .route("/api/localsend/v1/send", post(v1::handle_send))
+            .route("/api/localsend/v1/cancel", post(v1::handle_cancel))
             .with_state(crate::api::AppState {
-                handel: Arc::new(ServerHandle { inner_sender: itx }),
+                handel: Arc::new(self.handle.clone()),
             });
-        let addr = format!("0.0.0.0:{}", self.state.setting.port).parse::<SocketAddrV4>()?;
-        let listener = tokio::net::TcpListener::bind(addr).await?;
 
-        log::info!("Server started on {addr:?}");
-        axum::serve(
-            listener,
-            http_server.into_make_service_with_connect_info::<SocketAddr>(),
-        )
-        .await?;
+        let mut rebind_rx = self.state.rebind.subscribe();
+        let mut listener = Some(listener);
+        while !self.state.stopping.is_cancelled() {
+            let listener = match listener.take() {
+                Some(listener) => Some(listener),
+                None => self
+                    .state
+                    .setting
+                    .read()
+                    .await
+                    .bind_http_listener()
+                    .await
+                    .map_err(|e| log::error!("Error rebinding http server: {e}"))
+                    .ok(),
+            };
+            let Some(listener) = listener else {
+                // 等待下一次设置更新
+                tokio::select! {
+                    _ = self.state.stopping.cancelled() => break,
+                    res = rebind_rx.changed() => res?,
+                }
+                continue;
+            };
+            let addr = listener.local_addr()?;
+            self.state.setting.write().await.bound_port = Some(addr.port());
+            let _ = self.state.sender.send(ServerMessage::Listening(addr)).await;
+            // 发送组播消息
+            self.state.announce().await;
 
+            log::info!("Server started on {addr:?}");
+            let mut shutdown_rx = rebind_rx.clone();
+            let stopping = self.state.stopping.clone();
+            let serve = axum::serve(
+                listener,
+                http_server
+                    .clone()
+                    .into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(async move {
+                // 不再接受新的连接，等待正在进行的传输完成
+                tokio::select! {
+                    _ = shutdown_rx.changed() => {}
+                    _ = stopping.cancelled() => {}
+                }
+            });
+            tokio::select! {
+                res = serve => res?,
+                _ = cancel.cancelled() => break,
+            }
+            // 设置改变，重新绑定
+            rebind_rx.borrow_and_update();
+        }
+
+        // 结束所有任务
+        cancel.cancel();
+        for task in tasks {
+            let _ = task.await;
+        }
+        log::info!("Server stopped");
         Ok(())
     }
 }
@@ -304,7 +882,9 @@ impl ServerState {
     pub async fn handle_inner_message(&self, message: InnerMessage) {
         match message {
             InnerMessage::GetMyself(tx) => {
-                let _ = tx.send(self.setting.to_device_message(None));
+                let mut myself = self.setting.read().await.to_device_message(None);
+                myself.download |= self.share.read().await.is_some();
+                let _ = tx.send(myself);
             }
             InnerMessage::AddDevice(fingerprint, addr, device) => {
                 let mut devices = self.devices.write().await;
@@ -324,22 +904,73 @@ impl ServerState {
                     let _ = tx.send(Some(device.1.clone()));
                 }
             }
-            InnerMessage::FilePrepareUpload(file_req, tx) => {
+            InnerMessage::FilePrepareUpload(file_req, mut tx) => {
+                let action = self.setting.read().await.accept_policy.evaluate(&file_req);
+                log::info!("accept policy for {}: {action:?}", file_req.info.alias);
+                match action {
+                    PolicyAction::Accept => {
+                        let agreed = file_req.files.keys().cloned().collect();
+                        let _ = tx.send(agreed);
+                        let _ = self
+                            .sender
+                            .send(ServerMessage::FileAutoAccepted(file_req))
+                            .await;
+                        return;
+                    }
+                    PolicyAction::Decline => {
+                        let _ = tx.send(HashSet::new());
+                        let _ = self
+                            .sender
+                            .send(ServerMessage::FileAutoDeclined(file_req))
+                            .await;
+                        return;
+                    }
+                    PolicyAction::Ask => {}
+                }
                 let (out_tx, out_rx) = oneshot::channel();
+                let free_space = self.setting.read().await.free_space();
                 let _ = self
                     .sender
-                    .send(ServerMessage::FilePrepareUpload(file_req, out_tx))
+                    .send(ServerMessage::FilePrepareUpload(
+                        file_req, free_space, out_tx,
+                    ))
                     .await;
-                // 等待外部同意文件上传请求
-                if let Ok(agreed) = out_rx.await {
+                // 等待外部同意文件上传请求，不阻塞其他内部消息
+                // 发送方断开后不再等待，外部可以从 `is_closed` 得知请求已失效
+                tokio::spawn(async move {
+                    let agreed = tokio::select! {
+                        agreed = out_rx => agreed.unwrap_or_default(),
+                        _ = tx.closed() => return,
+                    };
                     let _ = tx.send(agreed);
-                } else {
-                    let _ = tx.send(HashSet::new());
-                }
+                });
+            }
+            InnerMessage::ReceiveText(file_req, tx) => {
+                let action = self.setting.read().await.accept_policy.evaluate(&file_req);
+                let Some(text) = file_req.text().filter(|_| action != PolicyAction::Decline) else {
+                    let _ = tx.send(false);
+                    return;
+                };
+                let _ = self
+                    .sender
+                    .send(ServerMessage::TextReceived(
+                        file_req.info.clone(),
+                        text.to_string(),
+                    ))
+                    .await;
+                let _ = tx.send(true);
             }
             InnerMessage::AddMission(mission_id, mission) => {
+                let session_file = self.setting.read().await.session_file.clone();
                 let mut missions = self.misssions.write().await;
-                missions.insert(mission_id, mission);
+                missions.insert(mission_id, mission.clone());
+                let snapshot = self.snapshot_missions(&session_file, &missions);
+                drop(missions);
+                self.save_missions(session_file, snapshot).await;
+                let _ = self
+                    .sender
+                    .send(ServerMessage::MissionStarted(mission))
+                    .await;
             }
             InnerMessage::GetMission(mission_id, tx) => {
                 let missions = self.misssions.read().await;
@@ -349,10 +980,14 @@ impl ServerState {
                     let _ = tx.send(None);
                 }
             }
+            InnerMessage::ListMissions(tx) => {
+                let missions = self.misssions.read().await;
+                let _ = tx.send(missions.values().cloned().collect());
+            }
             InnerMessage::GetFileInfo(param, tx) => {
                 // 从 `self.missions` 中读取任务
-                let missions = self.misssions.read().await;
-                let mission = match missions.get(&param.session_id) {
+                let mut missions = self.misssions.write().await;
+                let mission = match missions.get_mut(&param.session_id) {
                     Some(mission) => mission,
                     None => {
                         let _ = tx.send(None);
@@ -367,6 +1002,7 @@ impl ServerState {
                         return;
                     }
                 };
+                mission.start_file(&param.file_id);
                 // 发送进度到外部
                 let (progress_tx, progress_rx) = watch::channel(0);
                 let _ = self
@@ -381,10 +1017,140 @@ impl ServerState {
                 let _ = tx.send(file.map(|file| (file, progress_tx)));
             }
             InnerMessage::GetStorePath(tx) => {
-                let _ = tx.send(self.setting.store_path.clone());
+                let _ = tx.send(self.setting.read().await.store_path.clone());
+            }
+            InnerMessage::GetDestination(session_id, file_id, tx) => {
+                // 先复制路由并释放设置锁，再读取任务
+                let (routes, store_path) = {
+                    let setting = self.setting.read().await;
+                    (setting.routes.clone(), setting.store_path.clone())
+                };
+                let missions = self.misssions.read().await;
+                let dir = match missions
+                    .get(&session_id)
+                    .and_then(|mission| Some((mission, mission.info_map.get(&file_id)?)))
+                {
+                    // 日期按任务创建的时间计算，续传时目录不变
+                    Some((mission, file)) => resolve_destination(
+                        &routes,
+                        &store_path,
+                        &mission.sender_device,
+                        file,
+                        DateTime::from_timestamp_millis(mission.created_at)
+                            .map(|time| time.with_timezone(&Local))
+                            .unwrap_or_else(Local::now),
+                    ),
+                    None => store_path,
+                };
+                let _ = tx.send(dir);
+            }
+            InnerMessage::UploadFinished(session_id, file_id, result) => {
+                let session_file = self.setting.read().await.session_file.clone();
+                let mut missions = self.misssions.write().await;
+                let completed = match missions.get_mut(&session_id) {
+                    Some(mission) => mission.finish_file(&file_id, result.clone()),
+                    None => false,
+                };
+                let snapshot = self.snapshot_missions(&session_file, &missions);
+                drop(missions);
+                self.save_missions(session_file, snapshot).await;
+                let _ = self
+                    .sender
+                    .send(ServerMessage::UploadFinished(
+                        session_id.clone(),
+                        file_id,
+                        result,
+                    ))
+                    .await;
+                if completed {
+                    self.finish_mission(&session_id, MissionState::Completed)
+                        .await;
+                }
+            }
+            InnerMessage::GetPin(tx) => {
+                let _ = tx.send(self.setting.read().await.pin.clone());
+            }
+            InnerMessage::CheckSize(sizes, tx) => {
+                let _ = tx.send(self.setting.read().await.check_size(&sizes));
+            }
+            InnerMessage::CheckDiskSpace(file_req, tx) => {
+                let res = self.setting.read().await.check_disk_space(&file_req);
+                if let Err(e) = &res {
+                    log::warn!("decline files from {}: {e}", file_req.info.alias);
+                    let _ = self
+                        .sender
+                        .send(ServerMessage::FileDeclined(file_req, e.clone()))
+                        .await;
+                }
+                let _ = tx.send(res);
+            }
+            InnerMessage::Admit(ip, fingerprint, endpoint, tx) => {
+                let setting = self.setting.read().await;
+                let admission = if setting.is_blocked(ip, fingerprint.as_deref()) {
+                    Admission::Blocked
+                } else if !self
+                    .limiter
+                    .write()
+                    .await
+                    .check(&setting.rate_limit, ip, endpoint)
+                {
+                    log::warn!("rate limited {endpoint:?} from {ip}");
+                    Admission::RateLimited
+                } else {
+                    Admission::Allowed
+                };
+                let _ = tx.send(admission);
+            }
+            InnerMessage::PrepareDownload(ip, user_agent, param, tx) => {
+                let Some(share) = self.share.read().await.clone() else {
+                    let _ = tx.send(Err(ShareError::NotSharing));
+                    return;
+                };
+                let mut myself = self.setting.read().await.to_device_message(None);
+                myself.download = true;
+                if let Some(session_id) = param.session_id.clone() {
+                    if share.has_session(&session_id, ip).await {
+                        let _ = tx.send(Ok(share.response(myself, session_id)));
+                        return;
+                    }
+                }
+                if !share.authorize(&param) {
+                    log::warn!("unauthorized prepare-download from {ip}");
+                    let _ = tx.send(Err(ShareError::Unauthorized));
+                    return;
+                }
+                if share.auto_accept {
+                    let session_id = share.add_session(ip).await;
+                    let _ = tx.send(Ok(share.response(myself, session_id)));
+                    return;
+                }
+                let (out_tx, out_rx) = oneshot::channel();
+                let _ = self
+                    .sender
+                    .send(ServerMessage::ShareAccess(ip, user_agent, out_tx))
+                    .await;
+                // 等待外部同意访问，不阻塞其他内部消息
+                tokio::spawn(async move {
+                    let res = match out_rx.await.unwrap_or_default() {
+                        true => {
+                            let session_id = share.add_session(ip).await;
+                            Ok(share.response(myself, session_id))
+                        }
+                        false => Err(ShareError::Declined),
+                    };
+                    let _ = tx.send(res);
+                });
+            }
+            InnerMessage::GetShare(tx) => {
+                let _ = tx.send(self.share.read().await.clone());
+            }
+            InnerMessage::Shutdown(drain) => {
+                self.shutdown(drain);
             }
             InnerMessage::CancelMission(mission_id) => {
-                let mission = self.misssions.write().await.remove(&mission_id);
+                let mission = self
+                    .finish_mission(&mission_id, MissionState::Cancelled)
+                    .await;
                 let _ = self
                     .sender
                     .send(ServerMessage::CancelMission(mission))
@@ -398,24 +1164,289 @@ impl ServerState {
             OutMessage::Refresh => {
                 // // 清空 devices，重新发现所有设备
                 // *self.devices.write().await = HashMap::new();
-                let myself = self.setting.to_device_message(None);
-                self.devices.write().await.insert(
-                    myself.fingerprint.clone(),
-                    ("0.0.0.0:0".parse().unwrap(), myself),
+                self.announce().await;
+            }
+            OutMessage::Shutdown(drain) => {
+                self.shutdown(drain);
+            }
+            OutMessage::CancelMission(mission_id) => {
+                // 移除任务后，剩余文件的上传请求会被拒绝
+                let mission = self
+                    .finish_mission(&mission_id, MissionState::Cancelled)
+                    .await;
+                let _ = self
+                    .sender
+                    .send(ServerMessage::CancelMission(mission))
+                    .await;
+            }
+            OutMessage::Share(share) => {
+                log::info!(
+                    "share {} file(s)",
+                    share.as_ref().map_or(0, |share| share.files.len())
                 );
-                let recv_addr = format!("{}:{}", self.setting.multicast_addr, self.setting.port)
-                    .parse::<SocketAddrV4>()
-                    .unwrap_or(SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 167), 53317));
-                let device_message = self.setting.to_device_message(Some(true));
-
-                // 发送组播消息
-                match multicast_message(&recv_addr, &device_message).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        log::error!("Send multicast message error: {}", e);
-                    }
+                *self.share.write().await = share;
+            }
+            OutMessage::UpdateSettings(setting) => {
+                let mut current = self.setting.write().await;
+                let rebind = current.needs_rebind(&setting);
+                if current.fingerprint != setting.fingerprint {
+                    self.devices.write().await.remove(&current.fingerprint);
                 }
+                log::info!("update settings: {setting:?}");
+                // 新屏蔽的设备从列表中移除，解除屏蔽后可以重新发现
+                self.devices.write().await.retain(|fingerprint, (addr, _)| {
+                    !setting.is_blocked(addr.ip(), Some(fingerprint))
+                });
+                global_throttle().set_limit(setting.bandwidth_limit);
+                // 实际绑定的端口由服务器维护，重新绑定后会更新；任务文件只在启动时设置
+                *current = ServerSetting {
+                    bound_port: current.bound_port,
+                    session_file: current.session_file.clone(),
+                    ..setting
+                };
+                drop(current);
+                if rebind {
+                    // 重新绑定后会再发送组播消息
+                    self.rebind.send_replace(());
+                } else {
+                    self.announce().await;
+                }
+            }
+        }
+    }
+
+    fn shutdown(&self, drain: Option<Duration>) {
+        log::info!("shutting down server, drain: {drain:?}");
+        self.stopping.cancel();
+        match drain {
+            Some(deadline) => {
+                let cancel = self.cancel.clone();
+                tokio::spawn(async move {
+                    tokio::select! {
+                        _ = time::sleep(deadline) => cancel.cancel(),
+                        _ = cancel.cancelled() => {}
+                    }
+                });
             }
+            None => self.cancel.cancel(),
         }
     }
+
+    // 移除任务并发出 `MissionFinished`
+    async fn finish_mission(&self, mission_id: &str, state: MissionState) -> Option<Mission> {
+        let session_file = self.setting.read().await.session_file.clone();
+        let mut missions = self.misssions.write().await;
+        let mut mission = missions.remove(mission_id)?;
+        let snapshot = self.snapshot_missions(&session_file, &missions);
+        drop(missions);
+        self.save_missions(session_file, snapshot).await;
+        mission.state = state;
+        log::info!("mission {mission_id} finished: {state:?}");
+        let _ = self
+            .sender
+            .send(ServerMessage::MissionFinished(mission.summary(state)))
+            .await;
+        Some(mission)
+    }
+
+    async fn expire_missions(&self) {
+        let timeout = self.setting.read().await.session_timeout;
+        let now = now_millis();
+        let expired = self
+            .misssions
+            .read()
+            .await
+            .values()
+            .filter(|mission| mission.is_expired(now, timeout.saturating_mul(1000) as i64))
+            .map(|mission| mission.id.clone())
+            .collect::<Vec<_>>();
+        for mission_id in expired {
+            self.finish_mission(&mission_id, MissionState::Expired)
+                .await;
+        }
+    }
+
+    // 持有任务写锁时调用，只做序列化；写文件在释放锁之后
+    fn snapshot_missions(
+        &self,
+        session_file: &Option<PathBuf>,
+        missions: &HashMap<String, Mission>,
+    ) -> Option<(u64, String)> {
+        session_file.as_ref()?;
+        match serde_json::to_string(missions) {
+            Ok(content) => Some((
+                self.missions_version.fetch_add(1, Ordering::Relaxed) + 1,
+                content,
+            )),
+            Err(e) => {
+                log::error!("serialize missions error: {e}");
+                None
+            }
+        }
+    }
+
+    async fn save_missions(&self, session_file: Option<PathBuf>, snapshot: Option<(u64, String)>) {
+        let (Some(path), Some((version, content))) = (session_file, snapshot) else {
+            return;
+        };
+        let mut saved_version = self.saved_version.lock().await;
+        if version <= *saved_version {
+            return;
+        }
+        let result = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || write_missions(&path, &content)
+        })
+        .await;
+        match result {
+            Ok(Ok(())) => *saved_version = version,
+            Ok(Err(e)) => log::error!("save missions to {path:?} error: {e}"),
+            Err(e) => log::error!("save missions to {path:?} error: {e}"),
+        }
+    }
+
+    async fn announce(&self) {
+        let setting = self.setting.read().await.clone();
+        let myself = setting.to_device_message(None);
+        self.devices.write().await.insert(
+            myself.fingerprint.clone(),
+            ("0.0.0.0:0".parse().unwrap(), myself),
+        );
+        let recv_addr = setting.multicast_socket_addr();
+        let device_message = setting.to_device_message(Some(true));
+
+        // 发送组播消息
+        let interfaces = setting.multicast_interfaces();
+        match multicast_message(&recv_addr, &device_message, &interfaces).await {
+            Ok(_) => {}
+            Err(e) => {
+                log::error!("Send multicast message error: {}", e);
+            }
+        }
+    }
+
+    async fn handle_multicast(&self, device_message: DeviceMessage, sender_addr: SocketAddr) {
+        if self
+            .setting
+            .read()
+            .await
+            .is_blocked(sender_addr.ip(), Some(&device_message.fingerprint))
+        {
+            return;
+        }
+        let mut devices = self.devices.write().await;
+        // 过滤已经存在的设备
+        if !devices.contains_key(&device_message.fingerprint) {
+            devices.insert(
+                device_message.fingerprint.to_owned(),
+                (sender_addr, device_message.clone()),
+            );
+            self.sender
+                .send(ServerMessage::DeviceConnect(sender_addr, device_message))
+                .await
+                .unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (_out_tx, out_rx) = mpsc::channel(8);
+        let setting = ServerSetting {
+            alias: "test_device".to_string(),
+            port: 0,
+            interface_addr: "127.0.0.1".to_string(),
+            fingerprint: "test-shutdown".to_string(),
+            ..Default::default()
+        };
+        let (server, mut server_rx) = Server::new(setting, out_rx);
+        let handle = server.handle();
+        let task = tokio::spawn(async move { server.start().await.map_err(|e| e.to_string()) });
+        // 等待服务器开始监听
+        loop {
+            if let Some(ServerMessage::Listening(_)) = server_rx.recv().await {
+                break;
+            }
+        }
+        handle.shutdown(Some(Duration::from_millis(100))).await;
+        let res = time::timeout(Duration::from_secs(5), task).await;
+        assert!(matches!(res, Ok(Ok(Ok(())))));
+    }
+
+    #[test]
+    fn test_bound_port() {
+        let setting = ServerSetting {
+            port: 53317,
+            bound_port: Some(53318),
+            session_file: Some(PathBuf::from("/tmp/sessions.json")),
+            ..Default::default()
+        };
+        assert_eq!(setting.to_device_message(None).port, Some(53318));
+        // 备用端口不保存到配置，也不会触发重新绑定
+        let json = serde_json::to_value(&setting).unwrap();
+        assert_eq!(json["port"], 53317);
+        assert!(json.get("boundPort").is_none());
+        assert!(json.get("sessionFile").is_none());
+        let loaded: ServerSetting = serde_json::from_value(json).unwrap();
+        assert_eq!(loaded.bound_port, None);
+        assert!(!setting.needs_rebind(&loaded));
+    }
+
+    #[test]
+    fn test_check_size() {
+        let setting = ServerSetting {
+            max_file_size: Some(100),
+            max_session_size: Some(150),
+            ..Default::default()
+        };
+        assert!(setting.check_size(&[]).is_ok());
+        assert!(setting.check_size(&[100, 50]).is_ok());
+        assert!(setting.check_size(&[101]).is_err());
+        assert!(setting.check_size(&[100, 51]).is_err());
+        assert!(setting.check_size(&[u64::MAX, u64::MAX]).is_err());
+        assert!(ServerSetting::default().check_size(&[u64::MAX]).is_ok());
+
+        assert!(check_free_space(100, 200, 100).is_ok());
+        assert!(check_free_space(101, 200, 100).is_err());
+        assert!(check_free_space(u64::MAX, 200, 100).is_err());
+    }
+
+    #[test]
+    fn test_check_disk_space() {
+        let store = std::env::temp_dir().join(format!("disk-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&store).unwrap();
+        let free = fs4::available_space(&store).unwrap();
+        let file = |id: &str, file_type: &str, size: u64| {
+            let file = FileInfo {
+                id: id.to_string(),
+                file_name: id.to_string(),
+                file_type: file_type.to_string(),
+                size,
+                ..Default::default()
+            };
+            (id.to_string(), file)
+        };
+        // 图片保存到还不存在的子目录，和保存目录在同一磁盘上
+        let setting = ServerSetting {
+            store_path: store.clone(),
+            disk_space_margin: 0,
+            routes: serde_json::from_str(
+                r#"[{"fileTypes": ["image/*"], "destination": "{store}/images/{alias}"}]"#,
+            )
+            .unwrap(),
+            ..Default::default()
+        };
+        let request = |size: u64| FileRequest {
+            info: DeviceMessage::default(),
+            files: HashMap::from([file("a", "image/png", size), file("b", "text/plain", size)]),
+        };
+        assert!(setting.check_disk_space(&request(free / 4)).is_ok());
+        // 单个文件放得下，合计放不下
+        assert!(setting.check_disk_space(&request(free / 4 * 3)).is_err());
+        std::fs::remove_dir_all(&store).unwrap();
+    }
 }
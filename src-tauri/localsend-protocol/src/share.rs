@@ -0,0 +1,470 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use crc32fast::Hasher;
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::RwLock,
+};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use crate::{
+    api::{admit, AppState, SessionId},
+    guard::Endpoint,
+    model::{DeviceMessage, DownloadParam, DownloadResponse, FileInfo, PrepareDownloadParam},
+    throttle::global_throttle,
+};
+
+// 浏览器打开根路径看到的页面
+const SHARE_PAGE: &str = include_str!("share.html");
+
+// 通过下载 API 分享的文件，浏览器可以打开根路径的网页下载
+#[derive(Debug, Clone)]
+pub struct Share {
+    pub files: HashMap<String, (PathBuf, FileInfo)>,
+    pub token: String,       // 分享链接中的 token，持有链接即可访问
+    pub pin: Option<String>, // 没有链接时输入 PIN 访问
+    pub auto_accept: bool,   // 访问不需要在应用中同意
+    sessions: Arc<RwLock<HashMap<String, IpAddr>>>, // 已同意的会话 id 和访问者的 IP
+}
+
+// prepare-download 被拒绝的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShareError {
+    NotSharing,   // 404
+    Unauthorized, // 401，token 和 PIN 都不对
+    Declined,     // 403
+}
+
+impl Share {
+    pub fn new(files: Vec<(PathBuf, FileInfo)>, pin: Option<String>, auto_accept: bool) -> Self {
+        Self {
+            files: files
+                .into_iter()
+                .map(|(path, info)| (info.id.clone(), (path, info)))
+                .collect(),
+            token: Uuid::new_v4().simple().to_string(),
+            pin: pin.filter(|pin| !pin.is_empty()),
+            auto_accept,
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    // 发给对方的链接
+    pub fn link(&self, addr: SocketAddr) -> String {
+        format!("http://{addr}/?token={}", self.token)
+    }
+
+    pub fn authorize(&self, param: &PrepareDownloadParam) -> bool {
+        let matches = |given: &Option<String>, expected: &str| {
+            given
+                .as_deref()
+                .is_some_and(|given| constant_time_eq(given, expected))
+        };
+        matches(&param.token, &self.token)
+            || self
+                .pin
+                .as_deref()
+                .is_some_and(|pin| matches(&param.pin, pin))
+    }
+
+    // 会话只对同意时的 IP 有效
+    pub async fn has_session(&self, session_id: &str, ip: IpAddr) -> bool {
+        self.sessions.read().await.get(session_id) == Some(&ip)
+    }
+
+    pub async fn add_session(&self, ip: IpAddr) -> String {
+        let session_id = Uuid::new_v4().to_string();
+        self.sessions.write().await.insert(session_id.clone(), ip);
+        session_id
+    }
+
+    pub fn response(&self, info: DeviceMessage, session_id: String) -> DownloadResponse {
+        DownloadResponse {
+            info,
+            session_id,
+            files: self
+                .files
+                .iter()
+                .map(|(id, (_, info))| (id.clone(), info.clone()))
+                .collect(),
+        }
+    }
+}
+
+pub async fn handle_page(State(state): State<AppState>) -> Response {
+    match state.handel.get_share().await {
+        Some(_) => Html(SHARE_PAGE).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+pub async fn handle_prepare_download(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(param): Query<PrepareDownloadParam>,
+    headers: HeaderMap,
+) -> Result<Json<DownloadResponse>, StatusCode> {
+    admit(&state, addr, "", Endpoint::PrepareDownload).await?;
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    match state
+        .handel
+        .prepare_download(addr.ip(), user_agent, param)
+        .await
+    {
+        Ok(resp) => Ok(Json(resp)),
+        Err(ShareError::NotSharing) => Err(StatusCode::NOT_FOUND),
+        Err(ShareError::Unauthorized) => Err(StatusCode::UNAUTHORIZED),
+        Err(ShareError::Declined) => Err(StatusCode::FORBIDDEN),
+    }
+}
+
+// 检查会话，返回分享的文件
+async fn shared_files(
+    state: &AppState,
+    session_id: &str,
+    ip: IpAddr,
+) -> Result<HashMap<String, (PathBuf, FileInfo)>, StatusCode> {
+    let share = state
+        .handel
+        .get_share()
+        .await
+        .ok_or(StatusCode::NOT_FOUND)?;
+    match share.has_session(session_id, ip).await {
+        true => Ok(share.files),
+        false => Err(StatusCode::FORBIDDEN),
+    }
+}
+
+pub async fn handle_download(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(param): Query<DownloadParam>,
+) -> Result<Response, StatusCode> {
+    let files = shared_files(&state, &param.session_id, addr.ip()).await?;
+    let (path, info) = files.get(&param.file_id).ok_or(StatusCode::NOT_FOUND)?;
+    log::info!("{} downloads {:?}", addr.ip(), path);
+    let size = match fs::metadata(path).await {
+        Ok(metadata) => metadata.len(),
+        Err(e) => {
+            log::error!("read {path:?} error: {e}");
+            return Err(StatusCode::NOT_FOUND);
+        }
+    };
+    let (mut writer, reader) = tokio::io::duplex(64 * 1024);
+    let path = path.clone();
+    tokio::spawn(async move {
+        if let Err(e) = copy_file(&path, &mut writer, size, None).await {
+            log::warn!("download {path:?} error: {e}");
+        }
+    });
+    let file_name = info.file_name.rsplit('/').next().unwrap_or_default();
+    Ok((
+        [
+            (header::CONTENT_TYPE, info.file_type.clone()),
+            (header::CONTENT_LENGTH, size.to_string()),
+            (header::CONTENT_DISPOSITION, content_disposition(file_name)),
+        ],
+        Body::from_stream(ReaderStream::new(reader)),
+    )
+        .into_response())
+}
+
+// 扩展：把所有分享的文件打包成一个 zip 下载
+pub async fn handle_download_zip(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(param): Query<SessionId>,
+) -> Result<Response, StatusCode> {
+    let files = shared_files(&state, &param.id, addr.ip()).await?;
+    let mut files = files.into_values().collect::<Vec<_>>();
+    files.sort_by(|a, b| a.1.file_name.cmp(&b.1.file_name));
+    let entries = zip_entries(&files).await.map_err(|e| {
+        log::error!("read shared files error: {e}");
+        StatusCode::NOT_FOUND
+    })?;
+    // 不支持 zip64
+    let size = zip_size(&entries).ok_or(StatusCode::NOT_IMPLEMENTED)?;
+    log::info!("{} downloads {} file(s) as zip", addr.ip(), entries.len());
+    let (mut writer, reader) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move {
+        if let Err(e) = write_zip(&mut writer, &entries).await {
+            log::warn!("download zip error: {e}");
+        }
+    });
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            // 出错时连接提前结束，客户端可以发现下载不完整
+            (header::CONTENT_LENGTH, size.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                content_disposition("localsend.zip"),
+            ),
+        ],
+        Body::from_stream(ReaderStream::new(reader)),
+    )
+        .into_response())
+}
+
+// 比较所有字节，耗时不随第一个不同字节的位置变化，避免从响应时间猜出 token
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// 文件名可能有非 ASCII 字符，用 RFC 5987 的格式
+fn content_disposition(file_name: &str) -> String {
+    let mut encoded = String::new();
+    for byte in file_name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    format!("attachment; filename*=UTF-8''{encoded}")
+}
+
+// 按全局限速复制文件，最多复制 `len` 字节，返回复制的字节数
+async fn copy_file<W: AsyncWrite + Unpin>(
+    path: &Path,
+    writer: &mut W,
+    len: u64,
+    mut crc: Option<&mut Hasher>,
+) -> io::Result<u64> {
+    let mut file = File::open(path).await?.take(len);
+    let mut buf = vec![0; 64 * 1024];
+    let mut total = 0;
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(total);
+        }
+        global_throttle().consume(n).await;
+        if let Some(crc) = crc.as_deref_mut() {
+            crc.update(&buf[..n]);
+        }
+        writer.write_all(&buf[..n]).await?;
+        total += n as u64;
+    }
+}
+
+// 修改时间转换为 zip 使用的 MS-DOS 格式（本地时间）
+fn dos_date_time(path: &Path) -> (u16, u16) {
+    use chrono::{Datelike, Local, Timelike};
+
+    let Ok(modified) = std::fs::metadata(path).and_then(|m| m.modified()) else {
+        return (0, 0x21); // 1980-01-01
+    };
+    let time = chrono::DateTime::<Local>::from(modified);
+    if time.year() < 1980 {
+        return (0, 0x21);
+    }
+    (
+        ((time.hour() << 11) | (time.minute() << 5) | (time.second() / 2)) as u16,
+        ((((time.year() - 1980) as u32) << 9) | (time.month() << 5) | time.day()) as u16,
+    )
+}
+
+// zip 中的一个文件，大小是开始下载时磁盘上的大小
+struct ZipEntry {
+    path: PathBuf,
+    name: String,
+    size: u64,
+}
+
+async fn zip_entries(files: &[(PathBuf, FileInfo)]) -> io::Result<Vec<ZipEntry>> {
+    let mut entries = vec![];
+    for (path, info) in files {
+        // 只保留相对路径，目录用 `/` 分隔
+        let Some(name) = info.relative_path() else {
+            continue;
+        };
+        let name = name
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let size = fs::metadata(path).await?.len();
+        entries.push(ZipEntry {
+            path: path.clone(),
+            name,
+            size,
+        });
+    }
+    Ok(entries)
+}
+
+// `write_zip` 输出的总大小：每个文件有文件头 30、data descriptor 16 和中央目录 46，
+// 文件名出现两次，最后是 22 字节的结束记录。超出 zip32 的限制时为 None
+fn zip_size(entries: &[ZipEntry]) -> Option<u64> {
+    if entries.len() > u16::MAX as usize {
+        return None;
+    }
+    let total = entries.iter().fold(22u64, |total, entry| {
+        total.saturating_add(92 + 2 * entry.name.len() as u64 + entry.size)
+    });
+    // 总大小不超过 u32 时，偏移和每个文件的大小也不会超过
+    (total <= u32::MAX as u64).then_some(total)
+}
+
+// 不压缩的 zip，边读边写，crc 和大小放在每个文件之后的 data descriptor 中
+async fn write_zip<W: AsyncWrite + Unpin>(writer: &mut W, entries: &[ZipEntry]) -> io::Result<()> {
+    const FLAGS: u16 = 0x0808; // data descriptor，文件名为 UTF-8
+    let too_large = || io::Error::other("zip64 is not supported");
+    let mut central = vec![];
+    let mut count = 0u16;
+    let mut offset = 0u64;
+    for ZipEntry { path, name, size } in entries {
+        let (time, date) = dos_date_time(path);
+
+        let mut header = vec![];
+        header.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        header.extend_from_slice(&20u16.to_le_bytes()); // 需要的版本
+        header.extend_from_slice(&FLAGS.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes()); // 不压缩
+        header.extend_from_slice(&time.to_le_bytes());
+        header.extend_from_slice(&date.to_le_bytes());
+        header.extend_from_slice(&[0; 12]); // crc 和大小在 data descriptor 中
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+        writer.write_all(&header).await?;
+
+        let mut crc = Hasher::new();
+        // 文件在下载期间变短时结束下载，总大小和 Content-Length 不符
+        if copy_file(path, writer, *size, Some(&mut crc)).await? != *size {
+            return Err(io::Error::other(format!(
+                "{path:?} changed during download"
+            )));
+        }
+        let size = *size;
+        let crc = crc.finalize();
+        let size32 = u32::try_from(size).map_err(|_| too_large())?;
+        let mut descriptor = vec![];
+        descriptor.extend_from_slice(&0x08074b50u32.to_le_bytes());
+        descriptor.extend_from_slice(&crc.to_le_bytes());
+        descriptor.extend_from_slice(&size32.to_le_bytes());
+        descriptor.extend_from_slice(&size32.to_le_bytes());
+        writer.write_all(&descriptor).await?;
+
+        central.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        central.extend_from_slice(&20u16.to_le_bytes()); // 创建的版本
+        central.extend_from_slice(&header[4..14]); // 需要的版本、标志、压缩方式和时间
+        central.extend_from_slice(&crc.to_le_bytes());
+        central.extend_from_slice(&size32.to_le_bytes());
+        central.extend_from_slice(&size32.to_le_bytes());
+        central.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        central.extend_from_slice(&[0; 12]); // extra、注释、磁盘号和属性
+        central.extend_from_slice(
+            &u32::try_from(offset)
+                .map_err(|_| too_large())?
+                .to_le_bytes(),
+        );
+        central.extend_from_slice(name.as_bytes());
+
+        offset += (header.len() + descriptor.len()) as u64 + size;
+        count = count.checked_add(1).ok_or_else(too_large)?;
+    }
+
+    let mut end = vec![];
+    end.extend_from_slice(&0x06054b50u32.to_le_bytes());
+    end.extend_from_slice(&[0; 4]); // 磁盘号
+    end.extend_from_slice(&count.to_le_bytes());
+    end.extend_from_slice(&count.to_le_bytes());
+    end.extend_from_slice(&(central.len() as u32).to_le_bytes());
+    end.extend_from_slice(
+        &u32::try_from(offset)
+            .map_err(|_| too_large())?
+            .to_le_bytes(),
+    );
+    end.extend_from_slice(&0u16.to_le_bytes());
+    writer.write_all(&central).await?;
+    writer.write_all(&end).await?;
+    writer.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_share() {
+        let share = Share::new(vec![], Some("1234".to_string()), false);
+        let param = |token: Option<&str>, pin: Option<&str>| PrepareDownloadParam {
+            token: token.map(str::to_string),
+            pin: pin.map(str::to_string),
+            ..Default::default()
+        };
+        assert!(share.authorize(&param(Some(&share.token), None)));
+        assert!(share.authorize(&param(None, Some("1234"))));
+        assert!(!share.authorize(&param(Some("bad"), Some("bad"))));
+        assert!(!Share::new(vec![], None, false).authorize(&param(None, None)));
+        assert!(!share.authorize(&param(Some(&share.token[1..]), Some("123"))));
+        assert!(constant_time_eq("abc", "abc"));
+        assert!(!constant_time_eq("abc", "abd"));
+        assert!(!constant_time_eq("abc", "ab"));
+        let ip = "192.168.1.2".parse().unwrap();
+        let session_id = share.add_session(ip).await;
+        assert!(share.has_session(&session_id, ip).await);
+        assert!(
+            !share
+                .has_session(&session_id, "192.168.1.3".parse().unwrap())
+                .await
+        );
+
+        assert_eq!(
+            content_disposition("a b文.txt"),
+            "attachment; filename*=UTF-8''a%20b%E6%96%87.txt"
+        );
+
+        let dir = std::env::temp_dir().join(format!("share-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).await.unwrap();
+        fs::write(dir.join("a.txt"), "abc").await.unwrap();
+        let info = FileInfo {
+            file_name: "dir/a.txt".to_string(),
+            size: 1,
+            ..Default::default()
+        };
+        // 大小以磁盘上的为准
+        let entries = zip_entries(&[(dir.join("a.txt"), info)]).await.unwrap();
+        assert_eq!(entries[0].size, 3);
+        let mut zip = vec![];
+        write_zip(&mut zip, &entries).await.unwrap();
+        assert_eq!(Some(zip.len() as u64), zip_size(&entries));
+        let large = ZipEntry {
+            path: PathBuf::new(),
+            name: "large".to_string(),
+            size: u32::MAX as u64,
+        };
+        assert_eq!(zip_size(&[large]), None);
+        // 文件头 30 + 文件名 9 + 数据 3 + data descriptor 16，之后是中央目录
+        assert_eq!(&zip[..4], &0x04034b50u32.to_le_bytes());
+        assert_eq!(&zip[30..42], b"dir/a.txtabc");
+        assert_eq!(&zip[46..50], &0x352441c2u32.to_le_bytes()); // crc32("abc")
+        let end = &zip[zip.len() - 22..];
+        assert_eq!(&end[..4], &0x06054b50u32.to_le_bytes());
+        assert_eq!(&end[10..12], &1u16.to_le_bytes());
+        assert_eq!(&end[16..20], &58u32.to_le_bytes());
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+}
@@ -0,0 +1,79 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+// 带宽限制，令牌为字节数，最多积攒一秒的令牌
+pub struct Throttle {
+    limit: AtomicU64, // 字节/秒，0 为不限速
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Throttle {
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            limit: AtomicU64::new(limit.unwrap_or(0)),
+            state: Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    pub fn set_limit(&self, limit: Option<u64>) {
+        self.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    // 消耗 `bytes` 个令牌，不够时等待
+    pub async fn consume(&self, bytes: usize) {
+        let wait = self.reserve_at(bytes, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    // 令牌可以透支，多个传输同时等待时按先后顺序分摊带宽
+    fn reserve_at(&self, bytes: usize, now: Instant) -> Duration {
+        let limit = self.limit.load(Ordering::Relaxed);
+        if limit == 0 {
+            return Duration::ZERO;
+        }
+        let mut state = self.state.lock().unwrap();
+        let (tokens, updated) = &mut *state;
+        let elapsed = now.saturating_duration_since(*updated).as_secs_f64();
+        *tokens = (*tokens + elapsed * limit as f64).min(limit as f64) - bytes as f64;
+        *updated = now;
+        match *tokens < 0.0 {
+            true => Duration::from_secs_f64(-*tokens / limit as f64),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+// 发送和接收共用的全局限速，由服务器设置中的 `bandwidth_limit` 控制
+pub fn global_throttle() -> &'static Throttle {
+    static THROTTLE: OnceLock<Throttle> = OnceLock::new();
+    THROTTLE.get_or_init(|| Throttle::new(None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve() {
+        let start = Instant::now();
+        let throttle = Throttle::new(Some(1000));
+        assert_eq!(throttle.reserve_at(500, start), Duration::from_millis(500));
+        assert_eq!(throttle.reserve_at(500, start), Duration::from_secs(1));
+        // 一秒后补充 1000 个令牌，刚好还清
+        let later = start + Duration::from_secs(1);
+        assert_eq!(throttle.reserve_at(0, later), Duration::ZERO);
+        // 空闲很久也最多积攒一秒的令牌
+        let idle = later + Duration::from_secs(10);
+        assert_eq!(throttle.reserve_at(1500, idle), Duration::from_millis(500));
+
+        throttle.set_limit(None);
+        assert_eq!(throttle.reserve_at(1 << 30, idle), Duration::ZERO);
+    }
+}
@@ -0,0 +1,302 @@
+use std::{collections::HashMap, error::Error, net::SocketAddr, path::PathBuf, time::Duration};
+
+use axum::{
+    extract::{ConnectInfo, Query, Request, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::{
+    api::{admit, create_mission, receive_upload, AppState},
+    guard::Endpoint,
+    mime::OCTET_STREAM,
+    model::{
+        DeviceMessage, DeviceType, FileInfo, FileRequest, FileResponse, UploadParam, TEXT_PLAIN,
+    },
+    request::upload_file,
+};
+
+// 协议 v1 兼容：v1 没有会话 id，上传只带文件 id 和 token，也没有 PIN 和续传
+
+// v1 的 `info` 只有设备名和类型
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SendRequestInfo {
+    pub alias: String,
+    #[serde(default)]
+    pub device_model: Option<String>,
+    #[serde(default)]
+    pub device_type: Option<DeviceType>,
+    #[serde(default)]
+    pub fingerprint: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SendRequest {
+    pub info: SendRequestInfo,
+    pub files: HashMap<String, FileInfo>,
+}
+
+impl SendRequest {
+    // 发给 v1 设备的请求，`fileType` 改为 v1 的类型
+    pub fn new(file_req: FileRequest) -> Self {
+        let info = file_req.info;
+        let files = file_req
+            .files
+            .into_iter()
+            .map(|(id, mut file)| {
+                file.file_type = to_v1_type(&file.file_type).to_string();
+                (id, file)
+            })
+            .collect();
+        Self {
+            info: SendRequestInfo {
+                alias: info.alias,
+                device_model: info.device_model,
+                device_type: info.device_type,
+                fingerprint: info.fingerprint,
+            },
+            files,
+        }
+    }
+
+    // 收到的 v1 请求中的文件，`fileType` 改为 MIME
+    pub fn mime_files(&self) -> HashMap<String, FileInfo> {
+        self.files
+            .iter()
+            .map(|(id, file)| {
+                let mut file = file.clone();
+                file.file_type = from_v1_type(&file.file_type, &file.file_name);
+                (id.clone(), file)
+            })
+            .collect()
+    }
+}
+
+// v1 的 `fileType` 只有 `image`、`video`、`pdf`、`text`、`apk` 和 `other`
+fn to_v1_type(mime: &str) -> &'static str {
+    match mime {
+        "application/pdf" => "pdf",
+        "application/vnd.android.package-archive" => "apk",
+        mime if mime.starts_with("image/") => "image",
+        mime if mime.starts_with("video/") => "video",
+        mime if mime.starts_with("text/") => "text",
+        _ => "other",
+    }
+}
+
+// 具体的 MIME 按文件名猜测，和 v1 类型不符时使用该类型的默认值
+fn from_v1_type(file_type: &str, file_name: &str) -> String {
+    let guess = mime_guess::from_path(file_name)
+        .first_raw()
+        .filter(|mime| to_v1_type(mime) == file_type || file_type == "other");
+    if let Some(mime) = guess {
+        return mime.to_string();
+    }
+    match file_type {
+        "image" => "image/*",
+        "video" => "video/*",
+        "pdf" => "application/pdf",
+        "text" => TEXT_PLAIN,
+        "apk" => "application/vnd.android.package-archive",
+        // 有些实现已经使用 MIME
+        mime if mime.contains('/') => mime,
+        _ => OCTET_STREAM,
+    }
+    .to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SendParam {
+    pub file_id: String,
+    pub token: String,
+}
+
+pub async fn handle_send_request(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<SendRequest>,
+) -> Result<Response, StatusCode> {
+    log::info!("v1 send-request: {:?}", &payload);
+    let files = payload.mime_files();
+    let info = payload.info;
+    admit(&state, addr, &info.fingerprint, Endpoint::PrepareUpload).await?;
+    // v1 无法校验 PIN
+    if state.handel.get_pin().await.is_some() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    // 注册过的设备使用注册时的信息
+    let registered = match info.fingerprint.is_empty() {
+        true => None,
+        false => state.handel.get_device(info.fingerprint.clone()).await,
+    };
+    let device = registered.unwrap_or(DeviceMessage {
+        alias: info.alias,
+        version: "1.0".to_string(),
+        device_model: info.device_model,
+        device_type: info.device_type,
+        fingerprint: info.fingerprint,
+        ..Default::default()
+    });
+    let file_req = FileRequest {
+        info: device.clone(),
+        files,
+    };
+    match create_mission(&state, addr, file_req, device).await {
+        // v1 只返回文件 id 到 token 的映射
+        Ok(Some(file_resp)) => Ok(Json(file_resp.files).into_response()),
+        Ok(None) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(resp) => Ok(resp),
+    }
+}
+
+pub async fn handle_send(
+    State(state): State<AppState>,
+    Query(param): Query<SendParam>,
+    request: Request,
+) -> Result<(), StatusCode> {
+    // token 是随机生成的，用它找到所在的任务
+    let session_id = state
+        .handel
+        .list_missions()
+        .await
+        .into_iter()
+        .find(|mission| mission.id_token_map.get(&param.file_id) == Some(&param.token))
+        .map(|mission| mission.id)
+        .ok_or(StatusCode::FORBIDDEN)?;
+    let upload_param = UploadParam {
+        session_id,
+        file_id: param.file_id,
+        token: param.token,
+        offset: None,
+    };
+    receive_upload(&state, upload_param, request).await
+}
+
+// 取消这个 IP 发来的所有任务
+pub async fn handle_cancel(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) {
+    for mission in state.handel.list_missions().await {
+        if mission.sender_ip == Some(addr.ip()) {
+            state.handel.cancel_mission(mission.id).await;
+        }
+    }
+}
+
+// 客户端：对方只支持 v1 时代替 `request::prepare_upload`，返回的 `session_id` 为空
+pub async fn prepare_upload(
+    file_req: FileRequest,
+    addr: &SocketAddr,
+) -> Result<FileResponse, Box<dyn Error>> {
+    let send_request = SendRequest::new(file_req);
+    let url = format!("http://{addr}/api/localsend/v1/send-request");
+    let response = Client::new()
+        .post(url)
+        .json(&send_request)
+        .timeout(Duration::from_secs(60))
+        .send()
+        .await?;
+    match response.status() {
+        StatusCode::FORBIDDEN => return Err("rejected by receiver".into()),
+        status if !status.is_success() => return Err(format!("send request: {status}").into()),
+        StatusCode::NO_CONTENT => return Ok(FileResponse::default()),
+        _ => {}
+    }
+    Ok(FileResponse {
+        session_id: String::new(),
+        files: response.json().await?,
+    })
+}
+
+pub async fn upload(
+    upload_param: UploadParam,
+    file_path: &PathBuf,
+    addr: &SocketAddr,
+    progress: Option<watch::Sender<usize>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let url = format!("http://{addr}/api/localsend/v1/send");
+    let param = SendParam {
+        file_id: upload_param.file_id,
+        token: upload_param.token,
+    };
+    upload_file(url, &param, file_path, 0, progress).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_v1_messages() {
+        // v1 客户端发出的请求
+        let req: SendRequest = serde_json::from_str(
+            r#"{
+                "info": {"alias": "Nice Orange", "deviceModel": "Samsung", "deviceType": "mobile"},
+                "files": {
+                    "f1": {"id": "f1", "fileName": "my image.png", "size": 324242, "fileType": "image", "preview": "*preview data*"},
+                    "f2": {"id": "f2", "fileName": "movie", "size": 10, "fileType": "video"},
+                    "f3": {"id": "f3", "fileName": "notes.zip", "size": 10, "fileType": "other"}
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(req.info.fingerprint, "");
+        let files = req.mime_files();
+        assert_eq!(files["f1"].file_type, "image/png");
+        assert_eq!(files["f1"].size, 324242);
+        assert_eq!(files["f2"].file_type, "video/*");
+        assert_eq!(files["f3"].file_type, "application/zip");
+
+        // v1 的文本消息
+        let req: SendRequest = serde_json::from_str(
+            r#"{
+                "info": {"alias": "Nice Orange", "deviceModel": "Samsung", "deviceType": "mobile"},
+                "files": {"t": {"id": "t", "fileName": "t.txt", "size": 5, "fileType": "text", "preview": "hello"}}
+            }"#,
+        )
+        .unwrap();
+        let file_req = FileRequest {
+            info: DeviceMessage::default(),
+            files: req.mime_files(),
+        };
+        assert_eq!(file_req.text(), Some("hello"));
+
+        // 发给 v1 设备时使用 v1 的类型
+        let file = |file_type: &str| FileInfo {
+            id: file_type.to_string(),
+            file_type: file_type.to_string(),
+            ..Default::default()
+        };
+        let req = SendRequest::new(FileRequest {
+            info: DeviceMessage::default(),
+            files: [
+                "image/jpeg",
+                "application/pdf",
+                "text/plain",
+                "application/zip",
+            ]
+            .into_iter()
+            .map(|t| (t.to_string(), file(t)))
+            .collect(),
+        });
+        let json = serde_json::to_value(&req).unwrap();
+        assert_eq!(json["files"]["image/jpeg"]["fileType"], "image");
+        assert_eq!(json["files"]["application/pdf"]["fileType"], "pdf");
+        assert_eq!(json["files"]["text/plain"]["fileType"], "text");
+        assert_eq!(json["files"]["application/zip"]["fileType"], "other");
+
+        // v1 的设备信息可能没有 `version` 和 `download`
+        let device: DeviceMessage =
+            serde_json::from_str(r#"{"alias": "Old Phone", "fingerprint": "abc"}"#).unwrap();
+        assert!(!device.download);
+        assert!(!device.is_v1());
+    }
+}
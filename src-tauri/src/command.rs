@@ -1,33 +1,55 @@
 use std::{
-    collections::HashMap,
-    net::{IpAddr, SocketAddr},
-    path::PathBuf,
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
 };
 
 use localsend_protocol::{
-    model::{FileInfo, FileRequest, UploadParam},
-    request::{prepare_upload, upload},
-    server::OutMessage,
+    history::{export_csv, export_json, now_millis, HistoryEntry},
+    interface::{filter_interfaces, list_interfaces},
+    model::FileInfo,
+    preview::PreviewGenerator,
+    request::send_text as send_text_message,
+    sender::{retry_failed, send_files, send_to_many, SendEvent},
+    server::{OutMessage, ServerSetting},
+    share::Share,
 };
 use tauri::Emitter;
+use tokio::sync::mpsc;
 
 use crate::model::AppState;
 
 #[tauri::command(async)]
 pub async fn get_device_info(app_state: tauri::State<'_, AppState>) -> Result<String, String> {
-    let device = app_state.setting.read().await.to_device_message(None);
-    let ipv4s = if_addrs::get_if_addrs()
-        .unwrap_or_default()
-        .into_iter()
-        .filter_map(|iface| match iface.addr {
-            if_addrs::IfAddr::V4(v4) if !v4.is_loopback() => Some(v4.ip.into()),
-            _ => None,
-        })
-        .collect::<Vec<IpAddr>>();
+    let setting = app_state.setting.read().await;
+    let device = setting.to_device_message(None);
+    let ipv4s = setting.multicast_interfaces();
 
     Ok(serde_json::json!((device, ipv4s)).to_string())
 }
 
+#[tauri::command(async)]
+pub async fn get_network_interfaces(
+    app_state: tauri::State<'_, AppState>,
+) -> Result<String, String> {
+    // 所有网卡以及当前是否被选中
+    let setting = app_state.setting.read().await;
+    let interfaces = list_interfaces();
+    let selected = filter_interfaces(
+        interfaces.clone(),
+        &setting.interfaces,
+        &setting.exclude_interfaces,
+    );
+    let interfaces = interfaces
+        .into_iter()
+        .map(|iface| {
+            let is_selected = selected.contains(&iface);
+            (iface, is_selected)
+        })
+        .collect::<Vec<_>>();
+    Ok(serde_json::json!(interfaces).to_string())
+}
+
 #[tauri::command(async)]
 pub async fn refresh(app_state: tauri::State<'_, AppState>) -> Result<(), String> {
     match app_state.sender.read().await.as_ref() {
@@ -41,12 +63,71 @@ pub async fn refresh(app_state: tauri::State<'_, AppState>) -> Result<(), String
     Ok(())
 }
 
+#[tauri::command(async)]
+pub async fn get_settings(app_state: tauri::State<'_, AppState>) -> Result<String, String> {
+    let setting = app_state.setting.read().await;
+    Ok(serde_json::json!(*setting).to_string())
+}
+
+// 启动时读取配置失败的原因，没有出错时为 None
+#[tauri::command]
+pub fn get_config_error(app_state: tauri::State<'_, AppState>) -> Option<String> {
+    app_state.config_error.clone()
+}
+
+#[tauri::command(async)]
+pub async fn update_settings(
+    app_state: tauri::State<'_, AppState>,
+    setting: ServerSetting,
+) -> Result<(), String> {
+    let mut setting = setting;
+    // 设备指纹不允许修改
+    setting.fingerprint = app_state.setting.read().await.fingerprint.clone();
+    setting.alias = setting.alias.trim().to_string();
+    setting.validate()?;
+    apply_settings(&app_state, setting).await
+}
+
+// 屏蔽设备，之后它的 register 和 prepare-upload 请求都会被拒绝
+#[tauri::command(async)]
+pub async fn block_device(
+    app_state: tauri::State<'_, AppState>,
+    fingerprint: String,
+) -> Result<(), String> {
+    let mut setting = app_state.setting.read().await.clone();
+    if setting.blocked_devices.contains(&fingerprint) {
+        return Ok(());
+    }
+    setting.blocked_devices.push(fingerprint);
+    apply_settings(&app_state, setting).await
+}
+
+// 保存配置并通知服务器
+async fn apply_settings(app_state: &AppState, mut setting: ServerSetting) -> Result<(), String> {
+    app_state
+        .save_config(&setting)
+        .map_err(|e| format!("save config error: {e}"))?;
+    let mut current = app_state.setting.write().await;
+    // 前端不知道实际绑定的端口和任务文件，沿用当前的值
+    setting.bound_port = current.bound_port;
+    setting.session_file = current.session_file.clone();
+    *current = setting.clone();
+    drop(current);
+    match app_state.sender.read().await.as_ref() {
+        Some(sender) => sender
+            .send(OutMessage::UpdateSettings(setting))
+            .await
+            .map_err(|e| e.to_string())?,
+        None => {
+            log::error!("OutMessage Sender is None?");
+        }
+    }
+    Ok(())
+}
+
 #[cfg(not(target_os = "android"))]
 #[tauri::command]
 pub async fn open_file_picker(app: tauri::AppHandle) -> Result<String, String> {
-    use std::fs;
-
-    use localsend_protocol::model::FileInfo;
     use tauri_plugin_dialog::DialogExt;
 
     let files = app
@@ -54,33 +135,32 @@ pub async fn open_file_picker(app: tauri::AppHandle) -> Result<String, String> {
         .file()
         .blocking_pick_files()
         .unwrap_or_default();
-    let mut id_path = HashMap::new();
-    let file_infos = files
-        .into_iter()
-        .map(|file_path| {
-            let path = file_path.as_path().unwrap();
-            let metadata = fs::metadata(path).unwrap();
-            let id = uuid::Uuid::new_v4().to_string();
-            id_path.insert(id.clone(), path.to_str().unwrap_or_default().to_string());
-            FileInfo {
-                id,
-                file_name: path
-                    .file_name()
-                    .unwrap_or_default()
-                    .to_string_lossy()
-                    .to_string(),
-                file_type: path
-                    .extension()
-                    .unwrap_or_default()
-                    .to_string_lossy()
-                    .to_string(),
-                size: metadata.len(),
-                sha256: None,
-                preview: None,
-            }
-        })
-        .collect::<Vec<FileInfo>>();
-    Ok(serde_json::json!([id_path, file_infos]).to_string())
+    let paths = files
+        .iter()
+        .filter_map(|file_path| file_path.as_path().map(|path| path.to_path_buf()))
+        .collect::<Vec<PathBuf>>();
+    picked_files(paths).await
+}
+
+// 选择文件夹，发送其中所有文件并保留目录结构
+#[cfg(not(target_os = "android"))]
+#[tauri::command]
+pub async fn open_folder_picker(app: tauri::AppHandle) -> Result<String, String> {
+    use localsend_protocol::model::collect_files;
+    use tauri_plugin_dialog::DialogExt;
+
+    let Some(folder) = app.dialog().file().blocking_pick_folder() else {
+        return Ok(serde_json::json!([{}, []]).to_string());
+    };
+    let folder = folder.as_path().ok_or("invalid folder path")?;
+    let files = collect_files(folder).map_err(|e| e.to_string())?;
+    Ok(files_json(files))
+}
+
+#[cfg(target_os = "android")]
+#[tauri::command]
+pub async fn open_folder_picker() -> Result<String, String> {
+    Err("folder picker is not supported on android".to_string())
 }
 
 #[cfg(target_os = "android")]
@@ -88,37 +168,52 @@ pub async fn open_file_picker(app: tauri::AppHandle) -> Result<String, String> {
 pub async fn open_file_picker(app: tauri::AppHandle) -> Result<String, String> {
     use file_picker_android::PickerPlugin;
     use tauri::Manager;
-    use std::fs;
 
     let picker_plugin = app.state::<PickerPlugin<tauri::Wry>>();
     let files = picker_plugin.pick_files().unwrap_or(Vec::new());
+    let paths = files
+        .iter()
+        .map(|file_path| file_path.as_path().to_path_buf())
+        .collect::<Vec<PathBuf>>();
+    picked_files(paths).await
+}
+
+// 两种文件选择器共用，文件类型由 `FileInfo::from_path` 识别，图片带上缩略图
+// 读取文件和生成缩略图都是阻塞操作，放到单独的线程中
+async fn picked_files(paths: Vec<PathBuf>) -> Result<String, String> {
+    let files = tokio::task::spawn_blocking(move || {
+        let preview = PreviewGenerator::default();
+        paths
+            .into_iter()
+            .filter_map(|path| match FileInfo::from_path(&path) {
+                Ok(mut file_info) => {
+                    preview.fill(&mut file_info, &path);
+                    Some((path, file_info))
+                }
+                Err(e) => {
+                    log::error!("read {path:?} error: {e}");
+                    None
+                }
+            })
+            .collect()
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+    Ok(files_json(files))
+}
+
+// 返回给前端的 `[id_path, file_infos]`
+fn files_json(files: Vec<(PathBuf, FileInfo)>) -> String {
     let mut id_path = HashMap::new();
-    let file_infos = files
-        .into_iter()
-        .map(|file_path| {
-            let path = file_path.as_path();
-            let metadata = fs::metadata(path).unwrap();
-            let id = uuid::Uuid::new_v4().to_string();
-            id_path.insert(id.clone(), path.to_str().unwrap_or_default().to_string());
-            FileInfo {
-                id,
-                file_name: path
-                    .file_name()
-                    .unwrap_or_default()
-                    .to_string_lossy()
-                    .to_string(),
-                file_type: path
-                    .extension()
-                    .unwrap_or_default()
-                    .to_string_lossy()
-                    .to_string(),
-                size: metadata.len(),
-                sha256: None,
-                preview: None,
-            }
-        })
-        .collect::<Vec<FileInfo>>();
-    Ok(serde_json::json!([id_path, file_infos]).to_string())
+    let mut file_infos = vec![];
+    for (path, file_info) in files {
+        id_path.insert(
+            file_info.id.clone(),
+            path.to_str().unwrap_or_default().to_string(),
+        );
+        file_infos.push(file_info);
+    }
+    serde_json::json!([id_path, file_infos]).to_string()
 }
 
 #[tauri::command(async)]
@@ -133,50 +228,236 @@ pub async fn prepare_upload_files(
     if let Err(e) = app.emit("upload", file_infos.clone()) {
         log::error!("emit error: {e:?}");
     }
-    let addr: SocketAddr = addr.parse().unwrap();
+    let addr: SocketAddr = addr.parse().map_err(|e| format!("invalid address: {e}"))?;
     let addr = SocketAddr::new(addr.ip(), port);
-    let info = app_state.setting.read().await.to_device_message(None);
-    let files = file_infos
-        .iter()
-        .map(|file_info| (file_info.id.to_owned(), file_info.clone()))
-        .collect::<HashMap<String, FileInfo>>();
-    let file_req = FileRequest { info, files };
-    let resp = prepare_upload(file_req, &addr)
-        .await
-        .map_err(|e| e.to_string())?;
-    let agreed_vec = resp
-        .files
-        .keys()
-        .map(|s| s.to_owned())
-        .collect::<Vec<String>>();
-    if let Err(e) = app.emit("agreed-upload", agreed_vec.clone()) {
-        log::error!("emit error: {e:?}");
-    }
-    let mut handles = vec![];
-    for id in agreed_vec {
-        let token = resp.files.get(&id).unwrap().clone();
-        let upload_param = UploadParam {
-            session_id: resp.session_id.clone(),
-            file_id: id.clone(),
-            token,
-        };
-        let file_path = PathBuf::from(id_path.get(&id).unwrap());
-        let addr = addr.clone();
-        let join_handle =
-            tokio::spawn(async move { upload(upload_param, &file_path, &addr).await });
-        handles.push(join_handle);
+    let files = files_with_paths(&id_path, file_infos)?;
+    let setting = app_state.setting.read().await.clone();
+    let started_at = now_millis();
+    // 同时上传的文件数和带宽由设置限制
+    let result = send_files(&setting, addr, &files, None, forward_send_events(app)).await;
+    app_state.track_send(&result, &files).await;
+    app_state
+        .record_history(HistoryEntry::sent(&result, &files, started_at))
+        .await;
+    match result.error {
+        Some(e) if !result.declined => Err(e),
+        _ => Ok(()),
     }
+}
 
-    for handle in handles {
-        match handle.await {
-            Ok(res) if res.is_err() => {
-                log::error!("upload error: {:?}", res.unwrap_err());
+// 把发送事件转发给前端的 "send-event"，被同意的文件另外发出 "agreed-upload"
+fn forward_send_events(app: tauri::AppHandle) -> mpsc::Sender<SendEvent> {
+    let (events_tx, mut events_rx) = mpsc::channel(64);
+    tokio::spawn(async move {
+        while let Some(event) = events_rx.recv().await {
+            if let SendEvent::Accepted { file_ids, .. } = &event {
+                if let Err(e) = app.emit("agreed-upload", file_ids) {
+                    log::error!("emit error: {e:?}");
+                }
             }
-            Ok(_) => {}
-            Err(e) => {
-                log::error!("upload error: {}", e);
+            if let Err(e) = app.emit("send-event", event) {
+                log::error!("emit error: {e:?}");
             }
         }
+    });
+    events_tx
+}
+
+fn files_with_paths(
+    id_path: &HashMap<String, String>,
+    file_infos: Vec<FileInfo>,
+) -> Result<Vec<(PathBuf, FileInfo)>, String> {
+    file_infos
+        .into_iter()
+        .map(|file_info| {
+            let path = id_path
+                .get(&file_info.id)
+                .ok_or(format!("no path for {}", file_info.file_name))?;
+            Ok((PathBuf::from(path), file_info))
+        })
+        .collect()
+}
+
+// 同时发送给多个设备，通过 "send-event" 汇报每个设备的进度，返回每个设备的结果
+#[tauri::command(async)]
+pub async fn send_to_devices(
+    app_state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+    id_path: HashMap<String, String>,
+    file_infos: Vec<FileInfo>,
+    targets: Vec<(String, u16)>,
+) -> Result<String, String> {
+    let targets = targets
+        .into_iter()
+        .map(|(addr, port)| {
+            let addr: SocketAddr = addr.parse().map_err(|e| format!("invalid address: {e}"))?;
+            Ok(SocketAddr::new(addr.ip(), port))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let files = files_with_paths(&id_path, file_infos)?;
+    let setting = app_state.setting.read().await.clone();
+    let started_at = now_millis();
+    let results = send_to_many(
+        setting,
+        targets,
+        files.clone(),
+        None,
+        forward_send_events(app),
+    )
+    .await;
+    for result in &results {
+        app_state.track_send(result, &files).await;
+        app_state
+            .record_history(HistoryEntry::sent(result, &files, started_at))
+            .await;
     }
-    Ok(())
+    Ok(serde_json::json!(results).to_string())
+}
+
+// 在原来的会话中重新上传失败的文件，返回新的结果
+#[tauri::command(async)]
+pub async fn retry_failed_files(
+    app_state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+    session_id: String,
+) -> Result<String, String> {
+    let (previous, files) = app_state
+        .failed_sends
+        .write()
+        .await
+        .remove(&session_id)
+        .ok_or(format!("no failed files in session {session_id}"))?;
+    let setting = app_state.setting.read().await.clone();
+    let started_at = now_millis();
+    let retried = previous.failed.keys().cloned().collect::<HashSet<_>>();
+    let result = retry_failed(&setting, previous, &files, forward_send_events(app)).await;
+    app_state.track_send(&result, &files).await;
+    // 只记录这次重试的文件
+    let retried_files = files
+        .iter()
+        .filter(|(_, info)| retried.contains(&info.id))
+        .cloned()
+        .collect::<Vec<_>>();
+    app_state
+        .record_history(HistoryEntry::sent(&result, &retried_files, started_at))
+        .await;
+    Ok(serde_json::json!(result).to_string())
+}
+
+#[tauri::command(async)]
+pub async fn send_text(
+    app_state: tauri::State<'_, AppState>,
+    addr: String,
+    port: u16,
+    text: String,
+) -> Result<(), String> {
+    let addr: SocketAddr = addr.parse().map_err(|e| format!("invalid address: {e}"))?;
+    let addr = SocketAddr::new(addr.ip(), port);
+    let setting = app_state.setting.read().await.clone();
+    send_text_message(&setting, &text, &addr, None)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+// 通过网页分享选择的文件，返回每个网卡对应的链接
+#[tauri::command(async)]
+pub async fn start_share(
+    app_state: tauri::State<'_, AppState>,
+    id_path: HashMap<String, String>,
+    file_infos: Vec<FileInfo>,
+    pin: Option<String>,
+    auto_accept: bool,
+) -> Result<String, String> {
+    let files = files_with_paths(&id_path, file_infos)?;
+    let share = Share::new(files, pin, auto_accept);
+    let setting = app_state.setting.read().await;
+    let links = setting
+        .multicast_interfaces()
+        .into_iter()
+        .map(|ip| share.link(SocketAddr::new(ip.into(), setting.listening_port())))
+        .collect::<Vec<_>>();
+    send_out_message(&app_state, OutMessage::Share(Some(share))).await?;
+    Ok(serde_json::json!(links).to_string())
+}
+
+#[tauri::command(async)]
+pub async fn stop_share(app_state: tauri::State<'_, AppState>) -> Result<(), String> {
+    send_out_message(&app_state, OutMessage::Share(None)).await
+}
+
+async fn send_out_message(app_state: &AppState, message: OutMessage) -> Result<(), String> {
+    match app_state.sender.read().await.as_ref() {
+        Some(sender) => sender.send(message).await.map_err(|e| e.to_string()),
+        None => Err("server is not running".to_string()),
+    }
+}
+
+// 按设备名、指纹或文件名搜索，`query` 为空时返回全部记录
+#[tauri::command(async)]
+pub async fn get_history(
+    app_state: tauri::State<'_, AppState>,
+    query: Option<String>,
+) -> Result<String, String> {
+    let entries = match query.as_deref().map(str::trim) {
+        Some(query) if !query.is_empty() => app_state.history.search(query),
+        _ => app_state.history.list(),
+    }
+    .map_err(|e| e.to_string())?;
+    Ok(serde_json::json!(entries).to_string())
+}
+
+#[tauri::command(async)]
+pub async fn clear_history(app_state: tauri::State<'_, AppState>) -> Result<(), String> {
+    app_state.history.clear().map_err(|e| e.to_string())
+}
+
+// 导出为 `json` 或 `csv`，返回保存的路径，取消时返回 None
+#[cfg(not(target_os = "android"))]
+#[tauri::command]
+pub async fn export_history(
+    app_state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+    format: String,
+) -> Result<Option<String>, String> {
+    use tauri_plugin_dialog::DialogExt;
+
+    let entries = app_state.history.list().map_err(|e| e.to_string())?;
+    let content = match format.as_str() {
+        "json" => export_json(&entries),
+        "csv" => export_csv(&entries),
+        _ => return Err(format!("unsupported format `{format}`")),
+    };
+    let Some(path) = app
+        .dialog()
+        .file()
+        .set_file_name(format!("localsend-history.{format}"))
+        .blocking_save_file()
+    else {
+        return Ok(None);
+    };
+    let path = path.as_path().ok_or("invalid file path")?.to_path_buf();
+    std::fs::write(&path, content).map_err(|e| e.to_string())?;
+    Ok(Some(path.to_string_lossy().to_string()))
+}
+
+#[cfg(target_os = "android")]
+#[tauri::command]
+pub async fn export_history(format: String) -> Result<Option<String>, String> {
+    Err(format!(
+        "exporting history as {format} is not supported on android"
+    ))
+}
+
+// 用系统文件管理器打开记录中的文件所在的目录
+#[tauri::command(async)]
+pub async fn open_containing_folder(path: String) -> Result<(), String> {
+    let path = Path::new(&path);
+    let dir = match path.is_dir() {
+        true => path,
+        false => path.parent().ok_or("no parent folder")?,
+    };
+    if !dir.is_dir() {
+        return Err(format!("{dir:?} no longer exists"));
+    }
+    open::that_detached(dir).map_err(|e| e.to_string())
 }
@@ -0,0 +1,133 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context};
+use localsend_protocol::{policy::AcceptPolicy, server::ServerSetting};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub const CONFIG_VERSION: u64 = 2;
+pub const CONFIG_FILE: &str = "config.json";
+pub const SESSION_FILE: &str = "sessions.json"; // 接收任务，重启后对方可以续传
+pub const FAILED_SENDS_FILE: &str = "failed-sends.json"; // 有文件上传失败的发送会话
+pub const HISTORY_FILE: &str = "history.jsonl"; // 传输记录，每行一条
+
+// 保存在配置目录下的设置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Config {
+    pub version: u64,
+    #[serde(flatten)]
+    pub setting: ServerSetting,
+}
+
+impl Config {
+    pub fn new(setting: ServerSetting) -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            setting,
+        }
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("read {path:?}"))?;
+        let value: Value =
+            serde_json::from_str(&text).with_context(|| format!("parse {path:?}"))?;
+        migrate(value)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // 先写临时文件再重命名，避免写一半时退出导致配置损坏
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+// 逐个版本升级旧的配置
+fn migrate(mut value: Value) -> anyhow::Result<Config> {
+    let Some(object) = value.as_object_mut() else {
+        bail!("config is not a json object");
+    };
+    let Some(mut version) = object.get("version").and_then(Value::as_u64) else {
+        bail!("config has no version");
+    };
+    if version > CONFIG_VERSION {
+        bail!("config version {version} is newer than supported {CONFIG_VERSION}");
+    }
+    while version < CONFIG_VERSION {
+        match version {
+            // v2: `autoAccept` 改为规则形式的 `acceptPolicy`
+            1 => {
+                let auto_accept = object
+                    .remove("autoAccept")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false);
+                if auto_accept {
+                    object.insert(
+                        "acceptPolicy".to_string(),
+                        serde_json::to_value(AcceptPolicy::accept_all())?,
+                    );
+                }
+            }
+            _ => unreachable!(),
+        }
+        version += 1;
+        log::info!("migrate config to version {version}");
+    }
+    object.insert("version".to_string(), version.into());
+    Ok(serde_json::from_value(value)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 第一版写入的配置，`autoAccept` 还是布尔值
+    const CONFIG_V1: &str = r#"{
+  "version": 1,
+  "alias": "my-laptop",
+  "deviceModel": "",
+  "deviceType": "desktop",
+  "protocol": "http",
+  "download": false,
+  "port": 53317,
+  "portFallback": 10,
+  "interfaceAddr": "0.0.0.0",
+  "interfaces": [],
+  "excludeInterfaces": ["docker*", "veth*", "br-*", "virbr*", "vmnet*", "vboxnet*", "tun*", "tap*", "utun*", "wg*", "zt*"],
+  "multicastAddr": "224.0.0.167",
+  "multicastPort": 53317,
+  "storePath": "/home/me/Downloads",
+  "fingerprint": "0c6e1b8a-3f5d-4a4e-9a47-2f7c1d2b9e10",
+  "pin": null,
+  "autoAccept": true
+}"#;
+
+    #[test]
+    fn test_migrate() {
+        let config = migrate(serde_json::from_str(CONFIG_V1).unwrap()).unwrap();
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.setting.accept_policy, AcceptPolicy::accept_all());
+        assert_eq!(config.setting.alias, "my-laptop");
+        assert_eq!(
+            config.setting.fingerprint,
+            "0c6e1b8a-3f5d-4a4e-9a47-2f7c1d2b9e10"
+        );
+
+        let manual = CONFIG_V1.replace(r#""autoAccept": true"#, r#""autoAccept": false"#);
+        let config = migrate(serde_json::from_str(&manual).unwrap()).unwrap();
+        assert_eq!(config.setting.accept_policy, AcceptPolicy::default());
+
+        // 当前版本原样读取，缺少版本号或版本更新时报错
+        let current = serde_json::to_value(&config).unwrap();
+        assert_eq!(current["version"], CONFIG_VERSION);
+        assert!(current.get("autoAccept").is_none());
+        assert!(migrate(current).is_ok());
+        assert!(migrate(serde_json::json!({ "alias": "a" })).is_err());
+        assert!(migrate(serde_json::json!({ "version": CONFIG_VERSION + 1 })).is_err());
+    }
+}
@@ -5,6 +5,7 @@ use model::AppState;
 use tauri::Manager;
 
 pub mod command;
+pub mod config;
 pub mod model;
 pub mod server;
 
@@ -20,11 +21,27 @@ pub async fn run() {
         .plugin(tauri_plugin_shell::init());
     #[cfg(target_os = "android")]
     let builder = builder.plugin(file_picker_android::init());
-    builder.invoke_handler(tauri::generate_handler![
+    builder
+        .invoke_handler(tauri::generate_handler![
             get_device_info,
+            get_network_interfaces,
             refresh,
+            get_settings,
+            get_config_error,
+            update_settings,
+            block_device,
             open_file_picker,
-            prepare_upload_files
+            open_folder_picker,
+            prepare_upload_files,
+            send_to_devices,
+            retry_failed_files,
+            get_history,
+            clear_history,
+            export_history,
+            open_containing_folder,
+            send_text,
+            start_share,
+            stop_share
         ])
         .setup(|app| {
             let store_path: PathBuf = match env::consts::OS {
@@ -33,7 +50,8 @@ pub async fn run() {
             };
 
             log::info!("store_path: {store_path:?}");
-            let app_state = AppState::new(store_path);
+            let config_dir = app.path().app_config_dir()?;
+            let app_state = AppState::new(config_dir, store_path);
             app.manage(app_state);
             let app_handle = app.handle().clone();
             tokio::spawn(async move {
@@ -1,39 +1,146 @@
 use localsend_protocol::{
+    history::{History, HistoryEntry},
     mission::Mission,
-    model::{DeviceMessage, DeviceType},
+    model::{DeviceMessage, DeviceType, FileInfo},
+    sender::SendResult,
     server::{OutMessage, ServerSetting},
 };
-use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 use tokio::sync::{mpsc, RwLock};
 use uuid::Uuid;
 
+use crate::config::{Config, CONFIG_FILE, FAILED_SENDS_FILE, HISTORY_FILE, SESSION_FILE};
+
+type FailedSends = HashMap<String, (SendResult, Vec<(PathBuf, FileInfo)>)>;
+
 pub struct AppState {
     pub setting: RwLock<ServerSetting>,
     pub devices: RwLock<HashMap<String, (SocketAddr, DeviceMessage)>>,
     pub misssions: RwLock<HashMap<String, Mission>>,
     pub sender: RwLock<Option<mpsc::Sender<OutMessage>>>,
+    pub failed_sends: RwLock<FailedSends>, // 有文件上传失败的会话，重启后也可以重试
+    pub history: History,
+    pub config_path: PathBuf,
+    pub config_error: Option<String>, // 配置读取失败时告诉用户，前端启动后显示
 }
 
 impl AppState {
-    pub fn new(store_path: PathBuf) -> Self {
-        let hostname = tauri_plugin_os::hostname();
+    pub fn new(config_dir: PathBuf, store_path: PathBuf) -> Self {
+        let config_path = config_dir.join(CONFIG_FILE);
         let device_type = match tauri_plugin_os::platform() {
             "windows" | "macos" | "linux" => DeviceType::Desktop,
             "ios" | "android" => DeviceType::Mobile,
             _ => DeviceType::Headless,
         };
-        let settings = ServerSetting {
-            alias: hostname,
-            device_type: Some(device_type),
-            store_path,
-            fingerprint: Uuid::new_v4().to_string(),
-            ..Default::default()
+        let mut config_error = None;
+        let mut settings = match Config::load(&config_path) {
+            Ok(config) => config.setting,
+            Err(e) => {
+                let first_launch = e
+                    .downcast_ref::<io::Error>()
+                    .is_some_and(|e| e.kind() == io::ErrorKind::NotFound);
+                // 配置损坏、版本更新或读取出错时先备份，不能直接覆盖
+                let mut save = first_launch;
+                if !first_launch {
+                    log::error!("load config error: {e:?}");
+                    let backup = config_path.with_extension("json.bak");
+                    config_error = Some(match std::fs::rename(&config_path, &backup) {
+                        Ok(()) => {
+                            save = true;
+                            format!("读取配置失败，已备份到 {backup:?} 并使用默认设置：{e:#}")
+                        }
+                        Err(rename_err) => {
+                            log::error!("backup config error: {rename_err:?}");
+                            format!("读取配置失败，本次使用默认设置：{e:#}")
+                        }
+                    });
+                }
+                let settings = ServerSetting {
+                    alias: tauri_plugin_os::hostname(),
+                    store_path: store_path.clone(),
+                    fingerprint: Uuid::new_v4().to_string(),
+                    ..Default::default()
+                };
+                if save {
+                    if let Err(e) = Config::new(settings.clone()).save(&config_path) {
+                        log::error!("save config error: {e:?}");
+                    }
+                }
+                settings
+            }
         };
+        settings.device_type = Some(device_type);
+        settings.session_file = Some(config_dir.join(SESSION_FILE));
+        if !settings.store_path.is_dir() {
+            log::warn!(
+                "store path {:?} not found, use default",
+                settings.store_path
+            );
+            settings.store_path = store_path;
+        }
         AppState {
             setting: RwLock::new(settings),
             devices: RwLock::new(HashMap::new()),
             misssions: RwLock::new(HashMap::new()),
             sender: RwLock::new(None),
+            failed_sends: RwLock::new(load_failed_sends(&config_dir.join(FAILED_SENDS_FILE))),
+            history: History::new(config_dir.join(HISTORY_FILE)),
+            config_path,
+            config_error,
+        }
+    }
+
+    // 记录有文件上传失败的会话，全部成功后移除
+    pub async fn track_send(&self, result: &SendResult, files: &[(PathBuf, FileInfo)]) {
+        let Some(session_id) = &result.session_id else {
+            return;
+        };
+        let mut failed_sends = self.failed_sends.write().await;
+        match result.failed.is_empty() {
+            true => failed_sends.remove(session_id),
+            false => failed_sends.insert(session_id.clone(), (result.clone(), files.to_vec())),
+        };
+        let path = self.config_path.with_file_name(FAILED_SENDS_FILE);
+        let res = serde_json::to_string(&*failed_sends)
+            .map_err(anyhow::Error::from)
+            .and_then(|text| Ok(std::fs::write(&path, text)?));
+        if let Err(e) = res {
+            log::error!("save failed sends error: {e:?}");
         }
     }
+
+    // 计算 hash 比较耗时，放到阻塞线程中
+    pub async fn record_history(&self, mut entry: HistoryEntry) {
+        let entry = tokio::task::spawn_blocking(move || {
+            entry.fill_hashes();
+            entry
+        })
+        .await;
+        let res = match entry {
+            Ok(entry) => self.history.append(&entry).map_err(anyhow::Error::from),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = res {
+            log::error!("record history error: {e:?}");
+        }
+    }
+
+    pub fn save_config(&self, setting: &ServerSetting) -> anyhow::Result<()> {
+        Config::new(setting.clone()).save(&self.config_path)
+    }
+}
+
+fn load_failed_sends(path: &Path) -> FailedSends {
+    let Ok(text) = std::fs::read_to_string(path) else {
+        return HashMap::new();
+    };
+    serde_json::from_str(&text).unwrap_or_else(|e| {
+        log::warn!("load failed sends error: {e:?}");
+        HashMap::new()
+    })
 }
@@ -1,9 +1,13 @@
 use std::collections::HashSet;
 
 use crate::model::AppState;
-use localsend_protocol::server::{Server, ServerMessage};
+use localsend_protocol::{
+    history::HistoryEntry,
+    server::{Server, ServerMessage},
+};
 use tauri::{AppHandle, Emitter, Listener, Manager};
 use tokio::sync::mpsc;
+use uuid::Uuid;
 
 pub async fn run_server(app_handle: AppHandle) {
     let (out_tx, out_rx) = mpsc::channel(8);
@@ -39,8 +43,11 @@ async fn handle_server_message(m: ServerMessage, app_handle: AppHandle) {
                 .await
                 .insert(device.fingerprint.clone(), (addr, device));
         }
-        ServerMessage::FilePrepareUpload(file_req, agreed_tx) => {
-            if let Err(e) = app_handle.emit("file-prepare-upload", file_req) {
+        ServerMessage::FilePrepareUpload(file_req, free_space, agreed_tx) => {
+            // 额外带上保存目录的可用空间
+            let mut payload = serde_json::json!(file_req);
+            payload["freeSpace"] = serde_json::json!(free_space);
+            if let Err(e) = app_handle.emit("file-prepare-upload", payload) {
                 log::error!("emit error: {e:?}");
             }
             app_handle.once_any("agreed-set", |event| {
@@ -49,6 +56,23 @@ async fn handle_server_message(m: ServerMessage, app_handle: AppHandle) {
                 let _ = agreed_tx.send(agreed_set);
             });
         }
+        ServerMessage::FileAutoAccepted(file_req) => {
+            if let Err(e) = app_handle.emit("file-auto-accepted", file_req) {
+                log::error!("emit error: {e:?}");
+            }
+        }
+        ServerMessage::FileAutoDeclined(file_req) => {
+            log::info!(
+                "declined {} file(s) from {} by accept policy",
+                file_req.files.len(),
+                file_req.info.alias
+            );
+        }
+        ServerMessage::FileDeclined(file_req, reason) => {
+            if let Err(e) = app_handle.emit("file-declined", (&file_req, reason)) {
+                log::error!("emit error: {e:?}");
+            }
+        }
         ServerMessage::Progress(file_id, mut rx) => {
             let app_handle = app_handle.clone();
             tokio::spawn(async move {
@@ -61,8 +85,45 @@ async fn handle_server_message(m: ServerMessage, app_handle: AppHandle) {
                 // println!("file_id: {file_id}, finished");
             });
         }
-        ServerMessage::CancelMission(_mission_id) => {
-            // TODO:
+        ServerMessage::CancelMission(_) => {}
+        ServerMessage::MissionStarted(_) => {}
+        ServerMessage::UploadFinished(session_id, file_id, Err(e)) => {
+            log::warn!("failed to receive {file_id} in {session_id}: {e}");
+        }
+        ServerMessage::UploadFinished(..) => {}
+        ServerMessage::MissionFinished(summary) => {
+            if let Err(e) = app_handle.emit("mission-finished", &summary) {
+                log::error!("emit error: {e:?}");
+            }
+            app_state
+                .record_history(HistoryEntry::received(&summary))
+                .await;
+        }
+        ServerMessage::TextReceived(sender, text) => {
+            if let Err(e) = app_handle.emit("text-received", (sender, text)) {
+                log::error!("emit error: {e:?}");
+            }
+        }
+        ServerMessage::ShareAccess(ip, user_agent, allow_tx) => {
+            // 可能同时有多个访问请求，用请求 id 区分回复
+            let request_id = Uuid::new_v4().to_string();
+            let payload = serde_json::json!({
+                "requestId": request_id,
+                "ip": ip,
+                "userAgent": user_agent,
+            });
+            if let Err(e) = app_handle.emit("share-access", payload) {
+                log::error!("emit error: {e:?}");
+            }
+            app_handle.once_any(format!("share-access-{request_id}"), |event| {
+                let allowed = serde_json::from_str(event.payload()).unwrap_or(false);
+                let _ = allow_tx.send(allowed);
+            });
+        }
+        ServerMessage::Listening(addr) => {
+            // 端口可能因占用而改变，之后发出的设备信息需要使用实际端口
+            log::info!("server listening on {addr}");
+            app_state.setting.write().await.bound_port = Some(addr.port());
         }
     }
 }
@@ -12,6 +12,7 @@ export interface DeviceMessage {
 export interface FileRequest {
   info: DeviceMessage;
   files: Record<string, FileInfo>;
+  freeSpace?: number; // 保存目录的可用空间，只在文件传入请求中出现
 }
 
 export interface FileInfo {
@@ -20,8 +21,77 @@ export interface FileInfo {
   size: number; // bytes
   fileType: string;
   sha256?: string; // Optional
-  preview?: Uint8Array; // Optional
+  preview?: string; // Optional, 文本消息的内容或图片的 data URL
+  metadata?: { modified?: string; accessed?: string }; // ISO 8601
   downloaded?: number;
   speed?: number;
   progress?: number;
 }
+
+export interface SendResult {
+  target: string;
+  sessionId?: string;
+  accepted: string[];
+  finished: string[];
+  failed: Record<string, string>;
+  error?: string;
+  declined: boolean;
+}
+
+export type SendEvent =
+  | { event: "accepted"; target: string; sessionId: string; fileIds: string[] }
+  | { event: "progress"; target: string; fileId: string; bytes: number }
+  | {
+      event: "retrying";
+      target: string;
+      fileId: string;
+      attempt: number;
+      error: string;
+    }
+  | { event: "fileFinished"; target: string; fileId: string; error?: string }
+  | { event: "finished"; result: SendResult };
+
+export interface HistoryFile {
+  id: string;
+  fileName: string;
+  size: number;
+  path?: string; // 发送的源文件或接收后保存的位置
+  sha256?: string;
+  error?: string;
+}
+
+export interface HistoryEntry {
+  id: string;
+  sessionId?: string;
+  direction: "sent" | "received";
+  peerAlias: string;
+  peerFingerprint: string;
+  startedAt: number; // Unix 时间戳（毫秒）
+  durationMs: number;
+  outcome: "completed" | "partiallyFailed" | "failed" | "declined" | "cancelled";
+  error?: string;
+  files: HistoryFile[];
+}
+
+export interface FileStatus {
+  state: "pending" | "inProgress" | "finished" | "failed";
+  updatedAt: number;
+  path?: string;
+  error?: string;
+}
+
+export interface MissionSummary {
+  id: string;
+  senderDevice: DeviceMessage;
+  state: "active" | "completed" | "cancelled" | "expired";
+  createdAt: number;
+  finishedAt: number;
+  files: [FileInfo, FileStatus][];
+}
+
+// 浏览器请求访问分享的文件，回复 "share-access-<requestId>"
+export interface ShareAccess {
+  requestId: string;
+  ip: string;
+  userAgent?: string;
+}